@@ -0,0 +1,40 @@
+use std::process::Command;
+
+fn main() {
+    // Both are optional, /version leaves out whatever isn't known.
+    if let Some(commit) = git_commit() {
+        println!("cargo:rustc-env=GIT_COMMIT={commit}");
+    }
+    if let Some(version) = locked_version("terminus-store") {
+        println!("cargo:rustc-env=TERMINUS_STORE_VERSION={version}");
+    }
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+    println!("cargo:rerun-if-changed=../Cargo.lock");
+}
+
+fn git_commit() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
+
+/// The version of a dependency as resolved in the workspace lockfile.
+fn locked_version(package: &str) -> Option<String> {
+    let lock = std::fs::read_to_string("../Cargo.lock").ok()?;
+    let name_line = format!("name = \"{package}\"");
+    let mut lines = lock.lines();
+    lines.find(|line| *line == name_line)?;
+
+    lines
+        .next()?
+        .strip_prefix("version = \"")?
+        .strip_suffix('"')
+        .map(str::to_string)
+}
@@ -0,0 +1,108 @@
+use std::{io, net::IpAddr, path::Path};
+
+use hyper::Method;
+
+/// Which methods may be used from which networks. A request is allowed
+/// if any rule for a network containing its remote address allows its
+/// method.
+pub struct Acl {
+    rules: Vec<AclRule>,
+}
+
+struct AclRule {
+    network: IpAddr,
+    prefix_len: u32,
+    /// None means every method is allowed.
+    methods: Option<Vec<Method>>,
+}
+
+impl Acl {
+    /// Load an ACL from a file with one `cidr methods` rule per line,
+    /// where methods is a comma separated list or `*` for all of them.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut rules = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = parse_rule(line).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid ACL rule on line {}: {line}", number + 1),
+                )
+            })?;
+            rules.push(rule);
+        }
+
+        Ok(Acl { rules })
+    }
+
+    pub fn allows(&self, addr: IpAddr, method: &Method) -> bool {
+        // clients connecting over IPv4 to our IPv6 socket show up with
+        // a mapped address
+        let addr = addr.to_canonical();
+        self.rules.iter().any(|rule| {
+            rule.contains(addr)
+                && rule
+                    .methods
+                    .as_ref()
+                    .is_none_or(|methods| methods.contains(method))
+        })
+    }
+}
+
+impl AclRule {
+    fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn parse_rule(line: &str) -> Option<AclRule> {
+    let mut parts = line.split_whitespace();
+    let (cidr, methods) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(cidr), Some(methods), None) => (cidr, methods),
+        _ => return None,
+    };
+
+    let (network, prefix_len) = match cidr.split_once('/') {
+        Some((network, prefix_len)) => (network.parse().ok()?, prefix_len.parse().ok()?),
+        None => {
+            let network: IpAddr = cidr.parse().ok()?;
+            (network, if network.is_ipv4() { 32 } else { 128 })
+        }
+    };
+    let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+    if prefix_len > max_prefix_len {
+        return None;
+    }
+
+    let methods = if methods == "*" {
+        None
+    } else {
+        Some(
+            methods
+                .split(',')
+                .map(|method| Method::from_bytes(method.to_uppercase().as_bytes()).ok())
+                .collect::<Option<Vec<_>>>()?,
+        )
+    };
+
+    Some(AclRule {
+        network,
+        prefix_len,
+        methods,
+    })
+}
@@ -0,0 +1,33 @@
+use std::{collections::HashMap, io, path::Path};
+
+use super::name::{parse_layer_name, LayerName};
+
+/// Load a table of layer aliases. Every non-empty line that isn't a
+/// `#` comment holds an alias and the name it stands for, separated
+/// by whitespace.
+pub fn load_alias_map(path: impl AsRef<Path>) -> io::Result<HashMap<LayerName, LayerName>> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut aliases = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid alias on line {}: {line}", number + 1),
+            )
+        };
+        let mut names = line.split_whitespace();
+        let (alias, target) = match (names.next(), names.next(), names.next()) {
+            (Some(alias), Some(target), None) => (alias, target),
+            _ => return Err(invalid()),
+        };
+        let alias = parse_layer_name(alias).ok_or_else(invalid)?;
+        let target = parse_layer_name(target).ok_or_else(invalid)?;
+        aliases.insert(alias, target);
+    }
+
+    Ok(aliases)
+}
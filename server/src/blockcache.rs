@@ -0,0 +1,174 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, ErrorKind, SeekFrom},
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bytes::Bytes;
+use futures::Stream;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use super::name::LayerName;
+
+type BlockKey = (LayerName, usize);
+
+#[derive(Default)]
+struct Inner {
+    /// Every block along with the tick it was last used at
+    blocks: HashMap<BlockKey, (Bytes, u64)>,
+    /// Blocks by the tick they were last used at, oldest first
+    recency: BTreeMap<u64, BlockKey>,
+    tick: u64,
+}
+
+/// An LRU cache of fixed size blocks of layer files, shared by all
+/// requests. Blocks are keyed by layer and block index, which is safe
+/// because layers never change.
+pub struct BlockCache {
+    block_size: usize,
+    capacity: usize,
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub blocks: usize,
+    pub capacity: usize,
+}
+
+impl BlockCache {
+    /// A cache holding at most `size` bytes in blocks of `block_size`.
+    pub fn new(size: usize, block_size: usize) -> Self {
+        Self {
+            block_size,
+            capacity: size / block_size,
+            inner: Mutex::new(Inner::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self, key: BlockKey) -> Option<Bytes> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let (block, used) = match inner.blocks.get_mut(&key) {
+            Some(entry) => entry,
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        let block = block.clone();
+        let previous = std::mem::replace(used, tick);
+        inner.recency.remove(&previous);
+        inner.recency.insert(tick, key);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        Some(block)
+    }
+
+    fn insert(&self, key: BlockKey, block: Bytes) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        if let Some((_, previous)) = inner.blocks.insert(key, (block, tick)) {
+            inner.recency.remove(&previous);
+        }
+        inner.recency.insert(tick, key);
+        while inner.blocks.len() > self.capacity {
+            let (_, oldest) = inner.recency.pop_first().unwrap();
+            inner.blocks.remove(&oldest);
+        }
+    }
+
+    /// Drop all blocks of a layer, for when its file was replaced.
+    pub fn invalidate(&self, layer: LayerName) {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner {
+            blocks, recency, ..
+        } = &mut *inner;
+        blocks.retain(|(block_layer, _), (_, used)| {
+            if *block_layer == layer {
+                recency.remove(used);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        BlockCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            blocks: self.inner.lock().unwrap().blocks.len(),
+            capacity: self.capacity,
+        }
+    }
+
+    /// Stream a byte range of the archive of a layer, taking blocks from
+    /// the cache where possible and reading the rest from `reader`.
+    pub fn stream_range(
+        self: Arc<Self>,
+        layer: LayerName,
+        reader: File,
+        range: Range<usize>,
+    ) -> impl Stream<Item = io::Result<Bytes>> + Send {
+        futures::stream::unfold((reader, range.start), move |(mut reader, pos)| {
+            let cache = self.clone();
+            async move {
+                if pos >= range.end {
+                    return None;
+                }
+                let index = pos / cache.block_size;
+                let block = match cache.get((layer, index)) {
+                    Some(block) => block,
+                    None => match cache.read_block(&mut reader, index).await {
+                        Ok(block) => {
+                            cache.insert((layer, index), block.clone());
+                            block
+                        }
+                        Err(e) => return Some((Err(e), (reader, range.end))),
+                    },
+                };
+
+                let block_start = index * cache.block_size;
+                let from = pos - block_start;
+                let to = block.len().min(range.end - block_start);
+                if from >= to {
+                    let e = io::Error::new(ErrorKind::UnexpectedEof, "layer file is too short");
+                    return Some((Err(e), (reader, range.end)));
+                }
+
+                Some((Ok(block.slice(from..to)), (reader, block_start + to)))
+            }
+        })
+    }
+
+    async fn read_block(&self, reader: &mut File, index: usize) -> io::Result<Bytes> {
+        reader
+            .seek(SeekFrom::Start((index * self.block_size) as u64))
+            .await?;
+        let mut block = Vec::with_capacity(self.block_size);
+        (&mut *reader)
+            .take(self.block_size as u64)
+            .read_to_end(&mut block)
+            .await?;
+
+        Ok(block.into())
+    }
+}
@@ -0,0 +1,55 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+/// Size of a tar block. Headers take one, and file contents are padded
+/// to a whole number of them.
+const BLOCK_SIZE: usize = 512;
+
+/// The header of a tar entry for a regular file.
+pub fn entry_header(path: &str, size: u64) -> std::io::Result<Bytes> {
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let mut header = tar::Header::new_ustar();
+    header.set_path(path)?;
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_cksum();
+
+    Ok(Bytes::copy_from_slice(header.as_bytes()))
+}
+
+/// The zeroes that pad an entry of `size` bytes to a whole block.
+pub fn entry_padding(size: u64) -> Bytes {
+    let partial = (size % BLOCK_SIZE as u64) as usize;
+    match partial {
+        0 => Bytes::new(),
+        _ => Bytes::from(vec![0; BLOCK_SIZE - partial]),
+    }
+}
+
+/// The number of bytes taken up by an entry of `size` bytes, header
+/// and padding included.
+pub fn entry_len(size: u64) -> u64 {
+    BLOCK_SIZE as u64 + size.div_ceil(BLOCK_SIZE as u64) * BLOCK_SIZE as u64
+}
+
+/// The two zero blocks that end an archive.
+pub fn end_of_archive() -> Bytes {
+    Bytes::from(vec![0; 2 * BLOCK_SIZE])
+}
+
+/// A complete entry for a small file held in memory.
+pub fn small_entry(path: &str, contents: Bytes) -> std::io::Result<Bytes> {
+    let size = contents.len() as u64;
+    let mut entry = Vec::with_capacity(BLOCK_SIZE + contents.len() + BLOCK_SIZE);
+    entry.extend_from_slice(&entry_header(path, size)?);
+    entry.extend_from_slice(&contents);
+    entry.extend_from_slice(&entry_padding(size));
+
+    Ok(entry.into())
+}
@@ -0,0 +1,123 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use super::name::LayerName;
+
+#[derive(Default)]
+struct Inner {
+    /// Whether the local cache has been walked to find what's in it
+    loaded: bool,
+    /// Every cached layer along with its size and the tick it was last
+    /// used at
+    layers: HashMap<LayerName, (u64, u64)>,
+    /// Layers by the tick they were last used at, oldest first
+    recency: BTreeMap<u64, LayerName>,
+    tick: u64,
+    used: u64,
+}
+
+impl Inner {
+    fn insert(&mut self, layer: LayerName, size: u64) {
+        self.remove(layer);
+        self.tick += 1;
+        self.layers.insert(layer, (size, self.tick));
+        self.recency.insert(self.tick, layer);
+        self.used += size;
+    }
+
+    fn remove(&mut self, layer: LayerName) {
+        if let Some((size, used)) = self.layers.remove(&layer) {
+            self.recency.remove(&used);
+            self.used -= size;
+        }
+    }
+}
+
+/// Keeps track of how many bytes of layers are in the local cache, and
+/// which of them were used least recently, so the cache can be kept
+/// under a maximum size.
+///
+/// Nothing is known about the cache until `load` is called with what
+/// was found on disk. Until then, uses of layers are not recorded.
+pub struct CacheBudget {
+    max_bytes: u64,
+    inner: Mutex<Inner>,
+    /// Held while making room for a layer, so that two copies can't
+    /// both decide that the same free space is theirs.
+    pub evicting: tokio::sync::Mutex<()>,
+}
+
+impl CacheBudget {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            inner: Mutex::new(Inner::default()),
+            evicting: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    /// Bytes taken up by cached layers, including room reserved for
+    /// layers that are being copied.
+    pub fn used(&self) -> u64 {
+        self.inner.lock().unwrap().used
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.inner.lock().unwrap().loaded
+    }
+
+    /// Record the layers found in the local cache, along with their
+    /// sizes and access times.
+    pub fn load(&self, mut layers: Vec<(LayerName, u64, SystemTime)>) {
+        layers.sort_by_key(|(_, _, accessed)| *accessed);
+        let mut inner = self.inner.lock().unwrap();
+        for (layer, size, _) in layers {
+            inner.insert(layer, size);
+        }
+        inner.loaded = true;
+    }
+
+    /// Mark a layer as just used.
+    pub fn touch(&self, layer: LayerName) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        if let Some((_, used)) = inner.layers.get_mut(&layer) {
+            let previous = std::mem::replace(used, tick);
+            inner.recency.remove(&previous);
+            inner.recency.insert(tick, layer);
+        }
+    }
+
+    /// Whether `size` more bytes fit without evicting anything.
+    pub fn fits(&self, size: u64) -> bool {
+        self.used() + size <= self.max_bytes
+    }
+
+    /// Count a layer as cached, and as the most recently used one.
+    pub fn insert(&self, layer: LayerName, size: u64) {
+        self.inner.lock().unwrap().insert(layer, size);
+    }
+
+    pub fn remove(&self, layer: LayerName) {
+        self.inner.lock().unwrap().remove(layer);
+    }
+
+    /// All layers, least recently used first.
+    pub fn by_recency(&self) -> Vec<LayerName> {
+        self.inner
+            .lock()
+            .unwrap()
+            .recency
+            .values()
+            .copied()
+            .collect()
+    }
+}
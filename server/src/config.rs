@@ -0,0 +1,320 @@
+use clap::{Parser, ValueEnum};
+
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+pub struct Config {
+    /// Directory layers are stored in. Can be given more than once, in
+    /// which case layers are looked for in each in order, and uploads go
+    /// to the first.
+    #[arg(short, long, required = true)]
+    pub primary: Vec<String>,
+    /// Which copy of a layer is used when more than one primary has it
+    #[arg(long, value_enum, default_value_t = PrimaryConflict::FirstWins)]
+    pub primary_conflict: PrimaryConflict,
+    #[arg(short, long)]
+    pub local: String,
+    #[arg(short, long)]
+    pub upload: String,
+    #[arg(short, long)]
+    pub scratch: String,
+    /// Directory uploads are written to while they come in, before
+    /// being moved into the first primary. On the same filesystem as
+    /// that primary, such as a hidden directory inside it, the move is
+    /// an atomic rename rather than a copy. Defaults to --upload.
+    #[arg(long)]
+    pub upload_temp: Option<String>,
+    #[arg(long, default_value_t = 8080)]
+    pub port: u16,
+    /// Number of leading characters of a layer name used as the name of
+    /// the directory its file is stored in, in both primary and local.
+    /// With 0, all layer files are stored in a single directory.
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u8).range(0..=40))]
+    pub prefix_len: u8,
+    /// Return a JSON body naming the requested layer on 404 responses
+    #[arg(long)]
+    pub report_missing_layer: bool,
+    /// Leave out the caching headers that let CDNs treat layer downloads
+    /// as immutable
+    #[arg(long)]
+    pub no_cdn_headers: bool,
+    /// Don't send a `Link: rel=preload` header pointing at the parent
+    /// layer along with layer downloads
+    #[arg(long)]
+    pub no_parent_preload: bool,
+    /// Maximum number of concurrently streamed responses. Defaults to
+    /// a value derived from the soft RLIMIT_NOFILE.
+    #[arg(long)]
+    pub max_open_streams: Option<usize>,
+    /// Bearer token required for administrative endpoints and for
+    /// anything that writes, like uploads and caching requests.
+    /// Administrative endpoints are refused altogether without it.
+    #[arg(long)]
+    pub auth_token: Option<String>,
+    /// Require the --auth-token for reads as well
+    #[arg(long)]
+    pub auth_reads: bool,
+    /// File with rules of the form `cidr methods`, restricting which
+    /// methods may be used from which networks
+    #[arg(long)]
+    pub acl: Option<String>,
+    /// Origin browsers may fetch from this service from, as in
+    /// `https://app.example.com`, or `*` for any. Can be given more
+    /// than once. Without it, no CORS headers are sent and OPTIONS
+    /// requests are refused.
+    #[arg(long)]
+    pub cors_origin: Vec<String>,
+    /// Maximum number of layers being checksummed at the same time
+    #[arg(long, default_value_t = 2, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_concurrent_checksums: usize,
+    /// Maximum number of layers being uploaded at the same time. Not
+    /// limited if this isn't set.
+    #[arg(long)]
+    pub max_concurrent_uploads: Option<usize>,
+    /// Milliseconds an upload waits for one of the
+    /// --max-concurrent-uploads slots before being turned away with a 503
+    #[arg(long, default_value_t = 5000)]
+    pub upload_slot_timeout_ms: u64,
+    /// Read buffer size for primary files. When set, the kernel is also
+    /// advised that primary files are read sequentially, which makes
+    /// it prefetch aggressively. Useful for network mounted primaries.
+    #[arg(long)]
+    pub primary_readahead: Option<usize>,
+    /// How many times a failed copy into the local cache is retried.
+    /// Failures that retrying won't fix, like the layer having gone
+    /// from primary, are not retried.
+    #[arg(long, default_value_t = 3)]
+    pub copy_retries: u32,
+    /// Milliseconds to wait before the first copy retry. The wait
+    /// doubles with every further retry.
+    #[arg(long, default_value_t = 100)]
+    pub copy_retry_backoff_ms: u64,
+    /// Size in bytes of an in-process cache of layer file blocks shared
+    /// by all requests. No such cache is used if this isn't set.
+    #[arg(long)]
+    pub block_cache_size: Option<usize>,
+    /// Size in bytes of the blocks kept in the block cache
+    #[arg(long, default_value_t = 64 * 1024)]
+    pub block_cache_block_size: usize,
+    /// Hardlink layers into the local cache instead of copying them when
+    /// primary and local are on the same device
+    #[arg(long)]
+    pub hardlink_cache: bool,
+    /// Check copies into the local cache against the SHA-256 in a
+    /// `{name}.larch.sha256` file next to the layer in primary, if there
+    /// is one. Copies that don't match are thrown away.
+    #[arg(long)]
+    pub verify_cache_checksums: bool,
+    /// Don't fsync uploaded layers and the primary directory they are
+    /// moved into before answering an upload. Faster, but a crash can
+    /// then lose uploads that were reported as stored.
+    #[arg(long)]
+    pub no_durable_uploads: bool,
+    /// Fsync copies into the local cache and the directory they are
+    /// moved into. Without it, a crash can leave an empty or partial
+    /// layer file in the local cache.
+    #[arg(long)]
+    pub durable_cache: bool,
+    /// When reading a layer from primary fails with an I/O error, serve
+    /// a local copy instead, including copies that were invalidated
+    /// because primary changed
+    #[arg(long)]
+    pub serve_stale_on_primary_error: bool,
+    /// Maximum size in bytes of the layers in the local cache. Least
+    /// recently used layers are evicted to make room for new ones.
+    #[arg(long)]
+    pub max_local_cache_bytes: Option<u64>,
+    /// Percentage of the local disk in use above which layers stop being
+    /// cached, so they are served from primary instead
+    #[arg(long)]
+    pub cache_high_watermark: Option<u8>,
+    /// Percentage of the local disk in use below which caching resumes
+    /// after reaching --cache-high-watermark. Defaults to the high
+    /// watermark.
+    #[arg(long)]
+    pub cache_low_watermark: Option<u8>,
+    /// Maximum number of parsed layer headers kept in memory. The least
+    /// recently used ones are dropped first.
+    #[arg(long, default_value_t = 10_000)]
+    pub header_cache_entries: usize,
+    /// Parse the headers of all locally cached layers at startup, so
+    /// metadata requests for them don't have to. /readyz reports not
+    /// ready until this is done.
+    #[arg(long)]
+    pub preparse_headers: bool,
+    /// Report ready while --preparse-headers is still running
+    #[arg(long)]
+    pub preparse_background: bool,
+    /// Seconds for which the result of walking primary for
+    /// /admin/primary-usage is reused
+    #[arg(long, default_value_t = 300)]
+    pub primary_usage_cache_secs: u64,
+    /// Watch primary for layers that are modified or removed out of band
+    /// and drop them from the local cache. Watching a large primary is
+    /// costly, as every directory needs its own inotify watch.
+    #[arg(long)]
+    pub watch_primary: bool,
+    /// Maximum size in bytes of a layer uploaded through POST /layer,
+    /// after decompression. Also bounds resumable upload sessions.
+    #[arg(long)]
+    pub max_upload_size: Option<u64>,
+    /// Maximum size in bytes of responses that combine several files,
+    /// such as /batch and /bundle. Larger ones are refused up front.
+    #[arg(long)]
+    pub max_aggregate_response_size: Option<u64>,
+    /// Seconds after which an idle resumable upload session is discarded
+    #[arg(long, default_value_t = 3600)]
+    pub upload_session_ttl: u64,
+    /// File mapping layer names to the names they are stored under, one
+    /// `alias target` pair per line
+    #[arg(long)]
+    pub alias_map: Option<String>,
+    /// Redirect requests for aliased layers to the canonical path rather
+    /// than serving them transparently
+    #[arg(long)]
+    pub alias_redirect: bool,
+    /// Milliseconds for which a layer header parsed for /range is reused
+    /// by later /range requests on the same connection
+    #[arg(long)]
+    pub range_header_reuse_ms: Option<u64>,
+    /// Check that every parsed layer name formats back to the name it
+    /// was parsed from, rejecting it otherwise
+    #[arg(long)]
+    pub paranoid: bool,
+    /// Seconds allowed for metadata requests such as /range and /stats
+    #[arg(long)]
+    pub timeout_metadata: Option<u64>,
+    /// Seconds allowed for downloads, including streaming the body
+    #[arg(long)]
+    pub timeout_download: Option<u64>,
+    /// Seconds allowed for uploads, including receiving the body
+    #[arg(long)]
+    pub timeout_upload: Option<u64>,
+    /// Seconds a client may go without sending anything while request
+    /// headers or the body of an upload are expected. Stalled uploads are
+    /// answered with 408 and what was received of them is removed.
+    #[arg(long)]
+    pub timeout_idle: Option<u64>,
+    /// Log only one in this many requests to the access log. Server
+    /// errors and slow requests are always logged.
+    #[arg(long, default_value_t = 1)]
+    pub access_log_sample: u64,
+    /// Milliseconds after which a request counts as slow and is always
+    /// logged to the access log
+    #[arg(long)]
+    pub access_log_slow_ms: Option<u64>,
+    /// OTLP endpoint to export traces to. Without it, spans are only logged locally
+    #[arg(long)]
+    pub otlp_endpoint: Option<String>,
+    /// PEM file with the certificate chain to serve TLS with
+    #[arg(long)]
+    pub tls_cert: Option<String>,
+    /// PEM file with the private key belonging to --tls-cert
+    #[arg(long)]
+    pub tls_key: Option<String>,
+    /// Lowest TLS protocol version that will be negotiated
+    #[arg(long, value_enum, default_value_t = TlsVersion::V1_2)]
+    pub tls_min_version: TlsVersion,
+    /// Set of cipher suites offered during the TLS handshake
+    #[arg(long, value_enum, default_value_t = TlsCipherProfile::Compatible)]
+    pub tls_cipher_profile: TlsCipherProfile,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrimaryConflict {
+    /// The copy in the primary given first
+    FirstWins,
+    /// The most recently modified copy
+    NewestWins,
+    /// None; requests for the layer fail until the duplicates are
+    /// cleaned up
+    Error,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    #[value(name = "1.2")]
+    V1_2,
+    #[value(name = "1.3")]
+    V1_3,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsCipherProfile {
+    /// TLS 1.3 suites and ECDHE AEAD suites with 256 bit keys only
+    Modern,
+    /// The rustls defaults, all of which are forward secret AEAD suites
+    Compatible,
+}
+
+impl Config {
+    /// The effective configuration as JSON, with secrets redacted.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "primary": self.primary,
+            "primary_conflict": match self.primary_conflict {
+                PrimaryConflict::FirstWins => "first-wins",
+                PrimaryConflict::NewestWins => "newest-wins",
+                PrimaryConflict::Error => "error",
+            },
+            "local": self.local,
+            "upload": self.upload,
+            "scratch": self.scratch,
+            "upload_temp": self.upload_temp,
+            "port": self.port,
+            "prefix_len": self.prefix_len,
+            "report_missing_layer": self.report_missing_layer,
+            "no_cdn_headers": self.no_cdn_headers,
+            "no_parent_preload": self.no_parent_preload,
+            "max_open_streams": self.max_open_streams,
+            "auth_token": self.auth_token.as_ref().map(|_| "<redacted>"),
+            "auth_reads": self.auth_reads,
+            "acl": self.acl,
+            "cors_origin": self.cors_origin,
+            "max_concurrent_checksums": self.max_concurrent_checksums,
+            "max_concurrent_uploads": self.max_concurrent_uploads,
+            "upload_slot_timeout_ms": self.upload_slot_timeout_ms,
+            "primary_readahead": self.primary_readahead,
+            "copy_retries": self.copy_retries,
+            "copy_retry_backoff_ms": self.copy_retry_backoff_ms,
+            "block_cache_size": self.block_cache_size,
+            "block_cache_block_size": self.block_cache_block_size,
+            "hardlink_cache": self.hardlink_cache,
+            "verify_cache_checksums": self.verify_cache_checksums,
+            "no_durable_uploads": self.no_durable_uploads,
+            "durable_cache": self.durable_cache,
+            "serve_stale_on_primary_error": self.serve_stale_on_primary_error,
+            "max_local_cache_bytes": self.max_local_cache_bytes,
+            "cache_high_watermark": self.cache_high_watermark,
+            "cache_low_watermark": self.cache_low_watermark,
+            "header_cache_entries": self.header_cache_entries,
+            "preparse_headers": self.preparse_headers,
+            "preparse_background": self.preparse_background,
+            "primary_usage_cache_secs": self.primary_usage_cache_secs,
+            "watch_primary": self.watch_primary,
+            "max_upload_size": self.max_upload_size,
+            "max_aggregate_response_size": self.max_aggregate_response_size,
+            "upload_session_ttl": self.upload_session_ttl,
+            "alias_map": self.alias_map,
+            "alias_redirect": self.alias_redirect,
+            "range_header_reuse_ms": self.range_header_reuse_ms,
+            "paranoid": self.paranoid,
+            "timeout_metadata": self.timeout_metadata,
+            "timeout_download": self.timeout_download,
+            "timeout_upload": self.timeout_upload,
+            "timeout_idle": self.timeout_idle,
+            "access_log_sample": self.access_log_sample,
+            "access_log_slow_ms": self.access_log_slow_ms,
+            "otlp_endpoint": self.otlp_endpoint,
+            "tls_cert": self.tls_cert,
+            "tls_key": self.tls_key,
+            "tls_min_version": match self.tls_min_version {
+                TlsVersion::V1_2 => "1.2",
+                TlsVersion::V1_3 => "1.3",
+            },
+            "tls_cipher_profile": match self.tls_cipher_profile {
+                TlsCipherProfile::Modern => "modern",
+                TlsCipherProfile::Compatible => "compatible",
+            },
+        })
+    }
+}
@@ -0,0 +1,88 @@
+use hyper::{
+    header::{HeaderMap, HeaderValue},
+    Body, Request, Response,
+};
+
+const ALLOW_METHODS: &str = "GET, HEAD, POST, PATCH, DELETE, OPTIONS";
+/// Sent when a preflight doesn't say which headers it wants.
+const ALLOW_HEADERS: &str =
+    "Authorization, Content-Type, Content-Encoding, Range, If-None-Match, X-Request-Id";
+/// Response headers that scripts can't read unless they're listed.
+const EXPOSE_HEADERS: &str =
+    "Accept-Ranges, Content-Encoding, Content-Range, ETag, Link, Retry-After";
+
+/// Which origins browsers may fetch from this service. An origin of
+/// `*` lets any of them.
+pub struct Cors {
+    origins: Vec<String>,
+}
+
+impl Cors {
+    pub fn new(origins: Vec<String>) -> Option<Self> {
+        if origins.is_empty() {
+            return None;
+        }
+
+        Some(Cors { origins })
+    }
+
+    /// The `Access-Control-Allow-Origin` to answer a request from
+    /// `origin` with, if that origin is allowed.
+    fn allow_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        if self.origins.iter().any(|allowed| allowed == "*") {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let origin = origin?;
+        let text = origin.to_str().ok()?;
+        self.origins
+            .iter()
+            .any(|allowed| allowed == text)
+            .then(|| origin.clone())
+    }
+
+    /// Answer a preflight request. Origins that aren't allowed get no
+    /// CORS headers, which makes the browser refuse the real request.
+    pub fn preflight(&self, req: &Request<Body>) -> Response<Body> {
+        let mut response = Response::builder().status(204).body(Body::empty()).unwrap();
+        let origin = req.headers().get("Origin");
+        let headers = response.headers_mut();
+        self.add_origin(origin, headers);
+        if headers.contains_key("Access-Control-Allow-Origin") {
+            let allow_headers = req
+                .headers()
+                .get("Access-Control-Request-Headers")
+                .cloned()
+                .unwrap_or(HeaderValue::from_static(ALLOW_HEADERS));
+            headers.insert(
+                "Access-Control-Allow-Methods",
+                HeaderValue::from_static(ALLOW_METHODS),
+            );
+            headers.insert("Access-Control-Allow-Headers", allow_headers);
+            headers.insert("Access-Control-Max-Age", HeaderValue::from_static("86400"));
+        }
+
+        response
+    }
+
+    /// Add the CORS headers for a response to a request from `origin`.
+    pub fn add_headers(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        self.add_origin(origin, headers);
+        if headers.contains_key("Access-Control-Allow-Origin") {
+            headers.insert(
+                "Access-Control-Expose-Headers",
+                HeaderValue::from_static(EXPOSE_HEADERS),
+            );
+        }
+    }
+
+    fn add_origin(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        let allowed = self.allow_origin(origin);
+        if allowed.as_ref().is_none_or(|allowed| allowed != "*") {
+            // the answer depends on who is asking
+            headers.append("Vary", HeaderValue::from_static("Origin"));
+        }
+        if let Some(allowed) = allowed {
+            headers.insert("Access-Control-Allow-Origin", allowed);
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+/// A map holding at most `capacity` entries, which drops the least
+/// recently used entry to make room for a new one. It does no locking
+/// of its own.
+pub struct Lru<K, V> {
+    capacity: usize,
+    /// Every entry along with the tick it was last used at
+    entries: HashMap<K, (V, u64)>,
+    /// Keys by the tick they were last used at, oldest first
+    recency: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Copy, V> Lru<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        self.tick += 1;
+        let (value, used) = self.entries.get_mut(key)?;
+        let previous = std::mem::replace(used, self.tick);
+        self.recency.remove(&previous);
+        self.recency.insert(self.tick, *key);
+
+        Some(value)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&key);
+        while self.entries.len() >= self.capacity {
+            match self.recency.pop_first() {
+                Some((_, oldest)) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+        self.tick += 1;
+        self.entries.insert(key, (value, self.tick));
+        self.recency.insert(self.tick, key);
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, used) = self.entries.remove(key)?;
+        self.recency.remove(&used);
+
+        Some(value)
+    }
+}
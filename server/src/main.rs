@@ -1,34 +1,36 @@
+// Config::to_json lists every option in a single json! invocation.
+#![recursion_limit = "512"]
+
 use clap::Parser;
 
+mod acl;
+mod alias;
+mod blockcache;
+mod bundle;
+mod cachebudget;
+mod config;
+mod cors;
+mod lru;
 mod manager;
+mod metrics;
+mod name;
 mod server;
+mod session;
+mod stage;
+mod telemetry;
+#[cfg(test)]
+mod testutil;
+mod tls;
+mod warmup;
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    #[arg(short, long)]
-    primary: String,
-    #[arg(short, long)]
-    local: String,
-    #[arg(short, long)]
-    upload: String,
-    #[arg(short, long)]
-    scratch: String,
-    #[arg(short, long, default_value_t = 8080)]
-    port: u16,
-}
+use config::Config;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let args = Args::parse();
-    server::serve(
-        args.primary,
-        args.local,
-        args.upload,
-        args.scratch,
-        args.port,
-    )
-    .await?;
+    let config = Config::parse();
+    telemetry::init(&config)?;
+    let result = server::serve(config).await;
+    telemetry::shutdown();
 
-    Ok(())
+    result
 }
@@ -1,51 +1,618 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet, VecDeque},
     error::Error,
     io::{self, ErrorKind, SeekFrom},
     ops::Range,
     os::unix::prelude::MetadataExt,
-    path::{Path, PathBuf},
-    sync::Arc,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder};
 use async_tempfile::TempFile;
 use bytes::Bytes;
-use futures::Stream;
-use terminus_store::storage::{archive::ArchiveHeader, consts::LayerFileEnum, name_to_string};
+use futures::{
+    future::{BoxFuture, Shared},
+    Future, FutureExt, Stream, TryStreamExt,
+};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use num_traits::FromPrimitive;
+use sha2::{Digest, Sha256};
+use terminus_store::storage::{archive::ArchiveHeader, consts::LayerFileEnum};
 use tokio::{
     fs::File,
     io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
-    sync::Mutex,
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore, SemaphorePermit},
 };
 use tokio_stream::StreamExt;
-use tokio_util::io::ReaderStream;
+use tokio_util::{
+    either::Either,
+    io::{ReaderStream, StreamReader},
+};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use super::blockcache::{BlockCache, BlockCacheStats};
+use super::bundle;
+use super::cachebudget::CacheBudget;
+use super::config::{Config, PrimaryConflict};
+use super::lru::Lru;
+use super::metrics::Metrics;
+use super::name::{layer_name_to_string, parse_layer_name, LayerName};
+use super::server::{file_enum_to_string, frame_header};
+use super::session::{SessionError, UploadSessions};
+use super::stage::{missing_files, StageError, StagingArea};
+use super::warmup::WarmupTracker;
+
+/// File descriptors kept in reserve for sockets, directory walks and
+/// the copy tasks when deriving the stream limit from RLIMIT_NOFILE.
+const FD_HEADROOM: usize = 64;
+
+/// Marks the end of the footer appended by `get_layer_indexed`.
+const INDEX_FOOTER_MAGIC: &[u8; 8] = b"LARCHIDX";
+
+/// The files that make up the dictionary bundle of a layer.
+const DICTIONARY_FILES: [LayerFileEnum; 8] = [
+    LayerFileEnum::NodeDictionaryBlocks,
+    LayerFileEnum::NodeDictionaryOffsets,
+    LayerFileEnum::PredicateDictionaryBlocks,
+    LayerFileEnum::PredicateDictionaryOffsets,
+    LayerFileEnum::ValueDictionaryTypesPresent,
+    LayerFileEnum::ValueDictionaryTypeOffsets,
+    LayerFileEnum::ValueDictionaryBlocks,
+    LayerFileEnum::ValueDictionaryOffsets,
+];
+
+/// How many headers are parsed at the same time by `preparse_headers`.
+const PREPARSE_CONCURRENCY: usize = 16;
+
+/// Size of the buffer uploads are written to disk through.
+const UPLOAD_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Deepest layer stack /chain will follow before giving up.
+const MAX_CHAIN_DEPTH: usize = 10_000;
+
+/// How many shard directories `ensure_dir` remembers before starting
+/// over.
+const MAX_KNOWN_DIRS: usize = 4096;
 
 pub struct LayerManager {
-    primary_path: PathBuf,
+    /// Searched in order for layers. Uploads go to the first one.
+    primary_paths: Vec<PathBuf>,
+    primary_conflict: PrimaryConflict,
     local_path: PathBuf,
     upload_path: PathBuf,
+    /// Where uploads are written before they are moved into primary,
+    /// if not the upload path
+    upload_temp_path: Option<PathBuf>,
     scratch_path: PathBuf,
-    work_set: Mutex<HashSet<[u32; 5]>>,
+    /// Number of leading characters of a layer name that make up the
+    /// directory its file is stored in, in both primary and local.
+    prefix_len: usize,
+    work_set: Mutex<HashMap<LayerName, CopyTask>>,
+    metrics: Metrics,
+    open_streams: Arc<AtomicUsize>,
+    max_open_streams: usize,
+    upload_path_unwritable: AtomicUsize,
+    checksum_permits: Arc<Semaphore>,
+    upload_permits: Option<Semaphore>,
+    upload_slot_timeout: Duration,
+    primary_readahead: Option<usize>,
+    copy_retries: u32,
+    copy_retry_backoff: Duration,
+    block_cache: Option<Arc<BlockCache>>,
+    hardlink_cache: bool,
+    verify_cache_checksums: bool,
+    durable_uploads: bool,
+    durable_cache: bool,
+    serve_stale: bool,
+    cache_watermarks: Option<(u8, u8)>,
+    cache_paused: AtomicBool,
+    cache_budget: Option<CacheBudget>,
+    max_upload_size: Option<u64>,
+    idle_timeout: Option<Duration>,
+    upload_sessions: UploadSessions,
+    staging: StagingArea,
+    partial_layers: std::sync::Mutex<HashMap<LayerName, PartialLayer>>,
+    known_dirs: std::sync::Mutex<HashSet<PathBuf>>,
+    primary_usage: Mutex<Option<(Instant, PrimaryUsage)>>,
+    primary_usage_ttl: Duration,
+    warmups: WarmupTracker,
+    headers: std::sync::Mutex<Lru<LayerName, CachedHeader>>,
+    preparse: PreparseProgress,
+    /// Makes copies into the local cache panic halfway, for testing.
+    #[cfg(test)]
+    panic_during_copy: std::sync::atomic::AtomicBool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PrimaryUsage {
+    pub bytes: u64,
+    pub layers: usize,
+}
+
+/// A parsed archive header, along with where the header ends and the
+/// size of the file it was parsed from.
+#[derive(Clone)]
+struct CachedHeader {
+    header: ArchiveHeader,
+    header_len: u64,
+    size: usize,
+}
+
+/// How far along parsing the headers of local layers at startup is.
+#[derive(Default)]
+pub struct PreparseProgress {
+    done: AtomicUsize,
+    total: AtomicUsize,
+    finished: AtomicBool,
+}
+
+impl PreparseProgress {
+    pub fn done(&self) -> usize {
+        self.done.load(Ordering::Acquire)
+    }
+
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Acquire)
+    }
+
+    pub fn finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+}
+
+/// A layer that is still being written. Parts of it can already be
+/// served, up to the number of bytes written so far.
+struct PartialLayer {
+    path: PathBuf,
+    written: Arc<AtomicUsize>,
+}
+
+/// Returned (wrapped in an io error) when a request needs bytes of a
+/// partial layer that haven't been written yet.
+#[derive(Debug)]
+pub struct NotYetAvailable;
+
+impl std::fmt::Display for NotYetAvailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "requested range of the layer is not available yet")
+    }
+}
+
+impl Error for NotYetAvailable {}
+
+/// A layer archive is shorter than its header says it is.
+#[derive(Debug)]
+pub struct HeaderCorrupt {
+    pub expected: usize,
+    pub actual: usize,
+}
+
+impl std::fmt::Display for HeaderCorrupt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "layer header declares {} bytes but the archive is only {} bytes",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl Error for HeaderCorrupt {}
+
+/// A layer archive ends before its header does, as happens when it
+/// was cut short while being copied.
+#[derive(Debug)]
+pub struct HeaderTruncated {
+    pub size: usize,
+}
+
+impl std::fmt::Display for HeaderTruncated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "layer header is truncated at {} bytes", self.size)
+    }
+}
+
+impl Error for HeaderTruncated {}
+
+/// A byte range asked for in a `Range` header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// From `start` up to and including `end`, or to the end if absent
+    From { start: usize, end: Option<usize> },
+    /// The last so many bytes
+    Suffix(usize),
+}
+
+impl ByteRange {
+    /// The exclusive range this selects in something of the given
+    /// size, clamped to fit, or None if it selects nothing at all.
+    pub fn resolve(self, size: usize) -> Option<Range<usize>> {
+        let range = match self {
+            Self::From { start, end } => {
+                let end = end.map_or(size, |end| end.saturating_add(1).min(size));
+                start..end
+            }
+            Self::Suffix(len) => size.saturating_sub(len)..size,
+        };
+
+        (range.start < range.end).then_some(range)
+    }
+}
+
+/// A requested range lies entirely outside of a file.
+#[derive(Debug)]
+pub struct RangeNotSatisfiable {
+    pub size: usize,
+}
+
+impl std::fmt::Display for RangeNotSatisfiable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "requested range is outside of the {} byte file",
+            self.size
+        )
+    }
+}
+
+impl Error for RangeNotSatisfiable {}
+
+/// The result of looking up a file within a layer, telling apart a
+/// missing layer from a layer that lacks the file.
+pub enum FileLookup<T> {
+    Found(T),
+    LayerMissing,
+    FileMissing,
+}
+
+/// How the body of an upload is encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UploadEncoding {
+    Identity,
+    Gzip,
+}
+
+/// Wrapped in an io error when the upload dir can't be written to,
+/// which is a problem with the server rather than with the request.
+#[derive(Debug)]
+pub struct UploadPathUnwritable(io::Error);
+
+impl std::fmt::Display for UploadPathUnwritable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "upload path is not writable: {}", self.0)
+    }
+}
+
+impl Error for UploadPathUnwritable {}
+
+/// Wrapped in an io error when the primary path an upload is to be
+/// moved to turns out to be a directory.
+#[derive(Debug)]
+pub struct DestinationIsDirectory(PathBuf);
+
+impl std::fmt::Display for DestinationIsDirectory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "upload destination {:?} is a directory", self.0)
+    }
+}
+
+impl Error for DestinationIsDirectory {}
+
+/// Wrapped in an io error when a layer is in more than one primary and
+/// the conflict policy is to refuse it.
+#[derive(Debug)]
+pub struct LayerInSeveralPrimaries(Vec<PathBuf>);
+
+impl std::fmt::Display for LayerInSeveralPrimaries {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "layer is in more than one primary: {:?}", self.0)
+    }
+}
+
+impl Error for LayerInSeveralPrimaries {}
+
+/// Wrapped in an io error when the file handed to
+/// `move_uploaded_outside_layer` is somewhere it can't be taken from.
+#[derive(Debug)]
+pub struct BadUploadPath(&'static str);
+
+impl std::fmt::Display for BadUploadPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for BadUploadPath {}
+
+/// A copy of a layer doesn't hash to the checksum stored next to it in
+/// primary.
+#[derive(Debug)]
+pub struct ChecksumMismatch {
+    pub expected: String,
+    pub actual: String,
+}
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "copy hashes to {} rather than {}",
+            self.actual, self.expected
+        )
+    }
+}
+
+impl Error for ChecksumMismatch {}
+
+/// The ancestry of a layer can't be followed down to a base layer.
+#[derive(Debug)]
+pub struct BrokenChain(String);
+
+impl std::fmt::Display for BrokenChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "broken layer chain: {}", self.0)
+    }
+}
+
+impl Error for BrokenChain {}
+
+fn broken_chain(reason: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, BrokenChain(reason))
+}
+
+/// Returned when no upload slot became free in time.
+#[derive(Debug)]
+pub struct TooManyUploads;
+
+impl std::fmt::Display for TooManyUploads {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "too many concurrent uploads")
+    }
+}
+
+impl Error for TooManyUploads {}
+
+/// Returned when an upload exceeds the maximum upload size.
+#[derive(Debug)]
+pub struct UploadTooLarge {
+    pub limit: u64,
+}
+
+impl std::fmt::Display for UploadTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "upload exceeds the maximum size of {} bytes", self.limit)
+    }
+}
+
+impl Error for UploadTooLarge {}
+
+/// Returned when an upload stops sending data for too long.
+#[derive(Debug)]
+pub struct UploadStalled {
+    pub idle: Duration,
+}
+
+impl std::fmt::Display for UploadStalled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no upload data received for {:?}", self.idle)
+    }
+}
+
+impl Error for UploadStalled {}
+
+/// Returned when an upload isn't a layer archive that can be read back.
+#[derive(Debug)]
+pub struct InvalidArchive(String);
+
+impl std::fmt::Display for InvalidArchive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "upload is not a valid layer archive: {}", self.0)
+    }
+}
+
+impl Error for InvalidArchive {}
+
+fn not_yet_available() -> io::Error {
+    io::Error::new(ErrorKind::WouldBlock, NotYetAvailable)
+}
+
+/// Accounts for one open streaming response. The slot is released
+/// when the guard is dropped, which happens when the response body
+/// is either fully sent or abandoned.
+pub struct StreamGuard {
+    open_streams: Arc<AtomicUsize>,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.open_streams.fetch_sub(1, Ordering::AcqRel);
+    }
 }
 
 impl LayerManager {
-    pub fn new<P1: Into<PathBuf>, P2: Into<PathBuf>, P3: Into<PathBuf>, P4: Into<PathBuf>>(
-        primary_path: P1,
-        local_path: P2,
-        upload_path: P3,
-        scratch_path: P4,
-    ) -> Self {
+    pub fn new(config: &Config) -> Self {
+        let upload_temp = config.upload_temp.as_ref().unwrap_or(&config.upload);
         LayerManager {
-            primary_path: primary_path.into(),
-            local_path: local_path.into(),
-            upload_path: upload_path.into(),
-            scratch_path: scratch_path.into(),
-            work_set: Mutex::new(HashSet::new()),
+            primary_paths: config.primary.iter().map(PathBuf::from).collect(),
+            primary_conflict: config.primary_conflict,
+            local_path: config.local.clone().into(),
+            upload_path: config.upload.clone().into(),
+            upload_temp_path: config.upload_temp.as_ref().map(PathBuf::from),
+            scratch_path: config.scratch.clone().into(),
+            prefix_len: config.prefix_len as usize,
+            work_set: Mutex::new(HashMap::new()),
+            metrics: Metrics::new(),
+            open_streams: Arc::new(AtomicUsize::new(0)),
+            max_open_streams: config
+                .max_open_streams
+                .unwrap_or_else(default_max_open_streams),
+            upload_path_unwritable: AtomicUsize::new(0),
+            checksum_permits: Arc::new(Semaphore::new(config.max_concurrent_checksums)),
+            upload_permits: config.max_concurrent_uploads.map(Semaphore::new),
+            upload_slot_timeout: Duration::from_millis(config.upload_slot_timeout_ms),
+            primary_readahead: config.primary_readahead,
+            copy_retries: config.copy_retries,
+            copy_retry_backoff: Duration::from_millis(config.copy_retry_backoff_ms),
+            block_cache: config
+                .block_cache_size
+                .map(|size| Arc::new(BlockCache::new(size, config.block_cache_block_size.max(1)))),
+            hardlink_cache: config.hardlink_cache,
+            verify_cache_checksums: config.verify_cache_checksums,
+            durable_uploads: !config.no_durable_uploads,
+            durable_cache: config.durable_cache,
+            serve_stale: config.serve_stale_on_primary_error,
+            cache_watermarks: config
+                .cache_high_watermark
+                .map(|high| (high, config.cache_low_watermark.unwrap_or(high))),
+            cache_paused: AtomicBool::new(false),
+            cache_budget: config.max_local_cache_bytes.map(CacheBudget::new),
+            max_upload_size: config.max_upload_size,
+            idle_timeout: config.timeout_idle.map(Duration::from_secs),
+            upload_sessions: UploadSessions::new(
+                PathBuf::from(upload_temp),
+                Duration::from_secs(config.upload_session_ttl),
+                config.max_upload_size,
+            ),
+            staging: StagingArea::new(PathBuf::from(upload_temp)),
+            partial_layers: std::sync::Mutex::new(HashMap::new()),
+            known_dirs: std::sync::Mutex::new(HashSet::new()),
+            primary_usage: Mutex::new(None),
+            primary_usage_ttl: Duration::from_secs(config.primary_usage_cache_secs),
+            warmups: WarmupTracker::new(),
+            headers: std::sync::Mutex::new(Lru::new(config.header_cache_entries)),
+            preparse: PreparseProgress::default(),
+            #[cfg(test)]
+            panic_during_copy: Default::default(),
+        }
+    }
+
+    /// Reserve a slot for a streaming response, or return None if we
+    /// are too close to running out of file descriptors.
+    pub fn acquire_stream_slot(&self) -> Option<StreamGuard> {
+        let result = self
+            .open_streams
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                if count < self.max_open_streams {
+                    Some(count + 1)
+                } else {
+                    None
+                }
+            });
+
+        result.ok().map(|_| StreamGuard {
+            open_streams: self.open_streams.clone(),
+        })
+    }
+
+    pub fn open_streams(&self) -> usize {
+        self.open_streams.load(Ordering::Acquire)
+    }
+
+    pub fn max_open_streams(&self) -> usize {
+        self.max_open_streams
+    }
+
+    /// How often an upload failed because the upload dir wasn't writable.
+    pub fn upload_path_unwritable(&self) -> usize {
+        self.upload_path_unwritable.load(Ordering::Relaxed)
+    }
+
+    /// How long layers take from being requested cold to being served
+    /// from the local cache.
+    pub fn warmups(&self) -> &WarmupTracker {
+        &self.warmups
+    }
+
+    /// Whether caching is paused because the local disk went over the
+    /// high watermark.
+    pub fn cache_paused(&self) -> bool {
+        self.cache_paused.load(Ordering::Relaxed)
+    }
+
+    /// Check the local disk against the watermarks, and report whether
+    /// new layers may be cached.
+    pub fn caching_allowed(&self) -> bool {
+        let (high, low) = match self.cache_watermarks {
+            Some(watermarks) => watermarks,
+            None => return true,
+        };
+        let usage = match disk_usage_percent(&self.local_path) {
+            Ok(usage) => usage,
+            Err(e) => {
+                tracing::warn!("could not determine local disk usage: {e:?}");
+                return !self.cache_paused();
+            }
+        };
+
+        if usage >= high {
+            if !self.cache_paused.swap(true, Ordering::Relaxed) {
+                tracing::warn!("local disk is {usage}% full, pausing caching");
+            }
+        } else if usage < low && self.cache_paused.swap(false, Ordering::Relaxed) {
+            tracing::info!("local disk is {usage}% full, resuming caching");
+        }
+
+        !self.cache_paused()
+    }
+
+    /// Mark an error from creating a file in the upload dir if it means
+    /// that the dir isn't writable at all, as happens when its mount
+    /// goes read-only or fills up.
+    fn check_upload_path_error(&self, e: io::Error) -> io::Error {
+        let unwritable = e.kind() == ErrorKind::PermissionDenied
+            || e.raw_os_error() == Some(libc::EROFS)
+            || e.raw_os_error() == Some(libc::ENOSPC);
+        if !unwritable {
+            return e;
+        }
+
+        self.upload_path_unwritable.fetch_add(1, Ordering::Relaxed);
+        tracing::error!("upload path is not writable: {e:?}");
+        io::Error::new(e.kind(), UploadPathUnwritable(e))
+    }
+
+    /// The device id of each configured directory, by name. With more
+    /// than one primary, they are named `primary.0`, `primary.1` and so
+    /// on. `upload_temp` is only there if it was configured.
+    pub async fn path_devices(&self) -> Vec<(String, &Path, io::Result<u64>)> {
+        let primaries = self.primary_paths.iter().enumerate().map(|(index, path)| {
+            match self.primary_paths.len() {
+                1 => ("primary".to_string(), path),
+                _ => (format!("primary.{index}"), path),
+            }
+        });
+        let mut result = Vec::with_capacity(self.primary_paths.len() + 4);
+        let upload_temp = self
+            .upload_temp_path
+            .iter()
+            .map(|path| ("upload_temp".to_string(), path));
+        for (name, path) in primaries
+            .chain([
+                ("local".to_string(), &self.local_path),
+                ("upload".to_string(), &self.upload_path),
+                ("scratch".to_string(), &self.scratch_path),
+            ])
+            .chain(upload_temp)
+        {
+            let device = tokio::fs::metadata(path).await.map(|m| m.dev());
+            result.push((name, path.as_path(), device));
         }
+
+        result
     }
 
-    async fn file_reader(&self, path: &PathBuf) -> std::io::Result<Option<(usize, File)>> {
-        let size = match tokio::fs::metadata(&path).await {
+    async fn file_reader(
+        &self,
+        layer: LayerName,
+        path: &Path,
+    ) -> std::io::Result<Option<(usize, File)>> {
+        let size = match timed(layer, "metadata", tokio::fs::metadata(&path)).await {
             Ok(m) => m.size() as usize,
             Err(e) => match e.kind() {
                 ErrorKind::NotFound => return Ok(None),
@@ -57,8 +624,18 @@ impl LayerManager {
         options.create(false);
         options.read(true);
 
-        match options.open(&path).await {
-            Ok(r) => Ok(Some((size, r))),
+        match timed(layer, "open", options.open(&path)).await {
+            Ok(r) => {
+                if self.primary_readahead.is_some()
+                    && self
+                        .primary_paths
+                        .iter()
+                        .any(|primary| path.starts_with(primary))
+                {
+                    advise_sequential(&r);
+                }
+                Ok(Some((size, r)))
+            }
             Err(e) => match e.kind() {
                 ErrorKind::NotFound => Ok(None),
                 _ => Err(e),
@@ -66,49 +643,212 @@ impl LayerManager {
         }
     }
 
-    fn primary_layer_file_path(&self, layer: [u32; 5]) -> PathBuf {
-        let mut path = self.primary_path.clone();
-        let name = name_to_string(layer);
-        path.push(&name[0..3]);
-        path.push(format!("{name}.larch"));
+    /// Wrap a reader into a stream, using the configured read buffer size.
+    fn reader_stream<R: tokio::io::AsyncRead>(&self, reader: R) -> ReaderStream<R> {
+        match self.primary_readahead {
+            Some(capacity) => ReaderStream::with_capacity(reader, capacity),
+            None => ReaderStream::new(reader),
+        }
+    }
 
-        path
+    /// Make sure a directory exists. Directories that were created or
+    /// found before are assumed to still be there, which saves the
+    /// syscalls on every write into a shard that has been seen before.
+    /// There are up to 16^prefix_len shards per root, so the set is
+    /// cleared once it holds `MAX_KNOWN_DIRS` of them.
+    async fn ensure_dir(&self, dir: &Path) -> io::Result<()> {
+        if self.known_dirs.lock().unwrap().contains(dir) {
+            return Ok(());
+        }
+
+        if tokio::fs::create_dir_all(dir).await.is_err() {
+            // another task may have been removing or creating
+            // the same directory concurrently. Give it one more go.
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let mut known_dirs = self.known_dirs.lock().unwrap();
+        if known_dirs.len() >= MAX_KNOWN_DIRS {
+            known_dirs.clear();
+        }
+        known_dirs.insert(dir.to_path_buf());
+
+        Ok(())
+    }
+
+    fn forget_dir(&self, dir: &Path) {
+        self.known_dirs.lock().unwrap().remove(dir);
+    }
+
+    /// Rename a file to a path whose directory may not exist yet. If
+    /// the directory was assumed to exist but turns out to be gone,
+    /// it is created again.
+    async fn rename_into_dir(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let dir = to.parent().unwrap();
+        self.ensure_dir(dir).await?;
+        let result = match tokio::fs::rename(from, to).await {
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                self.forget_dir(dir);
+                self.ensure_dir(dir).await?;
+                tokio::fs::rename(from, to).await
+            }
+            result => result,
+        };
+        match result {
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => move_across_devices(from, to).await,
+            result => result,
+        }
+    }
+
+    /// Where uploads are written before they are moved into primary.
+    fn upload_temp_path(&self) -> &PathBuf {
+        self.upload_temp_path.as_ref().unwrap_or(&self.upload_path)
+    }
+
+    /// Warn if scratch and local are on different devices, which makes
+    /// every copy into the local cache a second copy rather than a
+    /// rename, and likewise if uploads are written to another device
+    /// than the first primary.
+    pub async fn check_devices(&self) {
+        let devices = self.path_devices().await;
+        let device_of = |name: &str| {
+            devices
+                .iter()
+                .find(|(n, _, _)| n == name)
+                .and_then(|(_, _, device)| device.as_ref().ok().copied())
+        };
+        if let (Some(local), Some(scratch)) = (device_of("local"), device_of("scratch")) {
+            if local != scratch {
+                tracing::warn!(
+                    "scratch and local are on different devices, so layers are copied \
+                     twice when they are cached"
+                );
+            }
+        }
+        let primary = match self.primary_paths.len() {
+            1 => "primary",
+            _ => "primary.0",
+        };
+        let upload_temp = match self.upload_temp_path {
+            Some(_) => "upload_temp",
+            None => "upload",
+        };
+        if let (Some(primary), Some(upload)) = (device_of(primary), device_of(upload_temp)) {
+            if primary != upload {
+                tracing::warn!(
+                    "{upload_temp} and the first primary are on different devices, so \
+                     uploads are copied into primary rather than renamed; consider \
+                     --upload-temp on the same filesystem as primary"
+                );
+            }
+        }
+    }
+
+    /// Where the file of a layer would be in each primary, in order.
+    fn primary_layer_file_paths(&self, layer: LayerName) -> impl Iterator<Item = PathBuf> + '_ {
+        let name = layer_name_to_string(layer);
+        self.primary_paths.iter().map(move |primary| {
+            let mut path = primary.clone();
+            path.push(&name[0..self.prefix_len]);
+            path.push(format!("{name}.larch"));
+
+            path
+        })
+    }
+
+    /// The file of a layer in primary. If more than one primary has it,
+    /// the conflict policy decides which one that is.
+    async fn find_primary_layer_file(&self, layer: LayerName) -> io::Result<Option<PathBuf>> {
+        if self.primary_conflict == PrimaryConflict::FirstWins {
+            for path in self.primary_layer_file_paths(layer) {
+                if tokio::fs::try_exists(&path).await? {
+                    return Ok(Some(path));
+                }
+            }
+
+            return Ok(None);
+        }
+
+        let mut found = Vec::new();
+        for path in self.primary_layer_file_paths(layer) {
+            match tokio::fs::metadata(&path).await {
+                Ok(metadata) => found.push((path, metadata.modified()?)),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if found.len() > 1 && self.primary_conflict == PrimaryConflict::Error {
+            let paths: Vec<_> = found.into_iter().map(|(path, _)| path).collect();
+            tracing::error!("layer is in more than one primary: {paths:?}");
+            return Err(io::Error::other(LayerInSeveralPrimaries(paths)));
+        }
+
+        // max_by_key picks the last of equals, so reversing lets the
+        // earlier primary win ties
+        Ok(found
+            .into_iter()
+            .rev()
+            .max_by_key(|(_, modified)| *modified)
+            .map(|(path, _)| path))
     }
 
     async fn primary_layer_file_reader(
         &self,
-        layer: [u32; 5],
+        layer: LayerName,
     ) -> std::io::Result<Option<(usize, File)>> {
-        let path = self.primary_layer_file_path(layer);
-        self.file_reader(&path).await
+        if self.primary_conflict != PrimaryConflict::FirstWins {
+            return match self.find_primary_layer_file(layer).await? {
+                Some(path) => self.file_reader(layer, &path).await,
+                None => Ok(None),
+            };
+        }
+
+        for path in self.primary_layer_file_paths(layer) {
+            if let Some(result) = self.file_reader(layer, &path).await? {
+                return Ok(Some(result));
+            }
+        }
+
+        Ok(None)
     }
 
-    fn local_layer_file_path(&self, layer: [u32; 5]) -> PathBuf {
+    fn local_layer_file_path(&self, layer: LayerName) -> PathBuf {
         let mut path = self.local_path.clone();
-        let name = name_to_string(layer);
-        path.push(&name[0..3]);
+        let name = layer_name_to_string(layer);
+        path.push(&name[0..self.prefix_len]);
         path.push(format!("{name}.larch"));
 
         path
     }
 
-    async fn local_layer_file_exists(&self, layer: [u32; 5]) -> std::io::Result<bool> {
+    fn dictionary_bundle_path(&self, layer: LayerName) -> PathBuf {
+        self.local_layer_file_path(layer)
+            .with_extension("dictionaries.gz")
+    }
+
+    /// Where an invalidated local copy is kept when stale copies may
+    /// still be served.
+    fn stale_layer_file_path(&self, layer: LayerName) -> PathBuf {
+        self.local_layer_file_path(layer)
+            .with_extension("larch.stale")
+    }
+
+    async fn local_layer_file_exists(&self, layer: LayerName) -> std::io::Result<bool> {
         let path = self.local_layer_file_path(layer);
         tokio::fs::try_exists(path).await
     }
 
     async fn local_layer_file_reader(
         &self,
-        layer: [u32; 5],
+        layer: LayerName,
     ) -> std::io::Result<Option<(usize, File)>> {
         let path = self.local_layer_file_path(layer);
 
-        self.file_reader(&path).await
+        self.file_reader(layer, &path).await
     }
 
-    fn scratch_layer_file_path(&self, layer: [u32; 5]) -> PathBuf {
+    fn scratch_layer_file_path(&self, layer: LayerName) -> PathBuf {
         let mut path = self.scratch_path.clone();
-        let name = name_to_string(layer);
+        let name = layer_name_to_string(layer);
         path.push(format!("{name}.larch"));
 
         path
@@ -116,168 +856,2248 @@ impl LayerManager {
 
     pub async fn get_layer_reader(
         self: Arc<Self>,
-        layer: [u32; 5],
+        layer: LayerName,
     ) -> std::io::Result<Option<(usize, File)>> {
         if let Some((size, reader)) = self.local_layer_file_reader(layer).await? {
-            Ok(Some((size, reader)))
-        } else if let Some((size, reader)) = self.primary_layer_file_reader(layer).await? {
-            // attempt to cache this file
-            self.clone().spawn_cache_layer(layer).await;
-            tokio::spawn(try_copy_layer(self.clone(), layer));
-            Ok(Some((size, reader)))
-        } else {
-            Ok(None)
+            self.warmups.served_locally(layer);
+            self.metrics.local_hit();
+            if let Some(budget) = &self.cache_budget {
+                budget.touch(layer);
+            }
+            tracing::debug!("cache hit");
+            return Ok(Some((size, reader)));
+        }
+        match self.primary_layer_file_reader(layer).await {
+            Ok(Some((size, reader))) => {
+                self.metrics.primary_fallback();
+                tracing::debug!("cache miss, reading from primary");
+                // attempt to cache this file
+                if self.caching_allowed() {
+                    self.warmups.requested(layer);
+                    self.clone().spawn_cache_layer(layer).await;
+                }
+                Ok(Some((size, reader)))
+            }
+            Ok(None) => Ok(None),
+            Err(e) if self.serve_stale => match self.stale_layer_reader(layer).await? {
+                Some(result) => {
+                    tracing::warn!(
+                        "reading layer {} from primary failed, serving stale copy: {e:?}",
+                        layer_name_to_string(layer)
+                    );
+                    Ok(Some(result))
+                }
+                None => Err(e),
+            },
+            Err(e) => Err(e),
         }
     }
 
-    pub async fn get_layer(
-        self: Arc<Self>,
-        layer: [u32; 5],
-    ) -> std::io::Result<Option<(usize, impl Stream<Item = io::Result<Bytes>> + Send)>> {
-        self.get_layer_reader(layer)
+    /// Find any local copy of a layer, including one that was
+    /// invalidated. A fresh copy may have appeared since we last looked.
+    async fn stale_layer_reader(&self, layer: LayerName) -> io::Result<Option<(usize, File)>> {
+        if let Some(result) = self.local_layer_file_reader(layer).await? {
+            return Ok(Some(result));
+        }
+
+        self.file_reader(layer, &self.stale_layer_file_path(layer))
             .await
-            .map(|result| result.map(|(size, reader)| (size, ReaderStream::new(reader))))
     }
 
-    pub async fn upload_layer(
+    /// Stream the archive of a layer, or the requested part of it. The
+    /// part that is streamed is returned along with the full size.
+    pub async fn get_layer(
         self: Arc<Self>,
-        layer: [u32; 5],
-        mut stream: impl Stream<Item = Result<Bytes, hyper::Error>> + Unpin,
-    ) -> Result<(), Box<dyn Error>> {
-        let mut file = TempFile::new_in(&self.upload_path).await?;
-        while let Some(mut bytes) = stream.try_next().await? {
-            file.write_all_buf(&mut bytes).await?;
+        layer: LayerName,
+        requested: Option<ByteRange>,
+        with_parent: bool,
+    ) -> std::io::Result<
+        Option<(
+            Range<usize>,
+            usize,
+            Option<LayerName>,
+            impl Stream<Item = io::Result<Bytes>> + Send,
+        )>,
+    > {
+        let (size, mut reader) = match self.clone().get_layer_reader(layer).await? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+        let part = resolve_requested(requested, size)?;
+        let parent = if with_parent {
+            self.parent_hint(layer, size, &mut reader).await
+        } else {
+            None
+        };
+        if (part.start != 0 || with_parent) && self.block_cache.is_none() {
+            timed(
+                layer,
+                "seek",
+                reader.seek(SeekFrom::Start(part.start as u64)),
+            )
+            .await?;
         }
-        file.flush().await?;
-
-        self.move_uploaded_layer(layer, file.file_path()).await?;
+        let stream = self.range_stream(layer, reader, part.clone());
 
-        Ok(())
+        Ok(Some((part, size, parent, stream)))
     }
 
-    async fn move_uploaded_layer(
+    /// A tar archive holding the archive of each given layer as
+    /// `{name}.larch`, in order, followed by a `manifest.json` entry
+    /// listing them, along with its size. Every layer is looked up
+    /// before anything is streamed, and if any aren't found, those are
+    /// returned instead. The layers are then opened one at a time as the
+    /// archive is read.
+    pub async fn bundle_layers(
         self: Arc<Self>,
-        layer: [u32; 5],
-        file_path: impl AsRef<Path>,
-    ) -> io::Result<()> {
-        {
-            // start out by making sure the file to be moved is
-            // actually fully on disk.
-            let file = tokio::fs::File::open(&file_path).await?;
-            file.sync_data().await?;
+        layers: Vec<LayerName>,
+    ) -> io::Result<Result<(usize, impl Stream<Item = io::Result<Bytes>> + Send), Vec<LayerName>>>
+    {
+        let mut sizes = Vec::with_capacity(layers.len());
+        let mut missing = Vec::new();
+        for &layer in &layers {
+            match self.clone().get_layer_reader(layer).await? {
+                Some((size, _)) => sizes.push(size),
+                None => missing.push(layer),
+            }
         }
-
-        let destination_path = self.primary_layer_file_path(layer);
-        if let Some(parent) = destination_path.parent() {
-            tokio::fs::create_dir_all(parent).await?;
+        if !missing.is_empty() {
+            return Ok(Err(missing));
         }
-        eprintln!("{:?}", file_path.as_ref());
-        tokio::fs::rename(file_path, &destination_path).await?;
 
-        self.spawn_cache_layer(layer).await;
+        let names: Vec<_> = layers
+            .iter()
+            .map(|&layer| layer_name_to_string(layer))
+            .collect();
+        let manifest = serde_json::json!({ "layers": names });
+        let manifest = bundle::small_entry("manifest.json", manifest.to_string().into())?;
+        let end = bundle::end_of_archive();
+        let size = sizes
+            .iter()
+            .map(|&size| bundle::entry_len(size as u64) as usize)
+            .sum::<usize>()
+            + manifest.len()
+            + end.len();
 
-        Ok(())
+        let manager = self;
+        let entries = futures::StreamExt::then(
+            futures::stream::iter(layers.into_iter().zip(sizes)),
+            move |(layer, size)| manager.clone().bundle_entry(layer, size),
+        )
+        .try_flatten()
+        .chain(futures::stream::iter([Ok(manifest), Ok(end)]));
+
+        Ok(Ok((size, entries)))
     }
 
-    pub async fn move_uploaded_outside_layer(
+    /// The tar entry of a layer in a bundle. The layer was found with
+    /// the given size when the bundle was started, and the body is cut
+    /// short if that no longer holds.
+    async fn bundle_entry(
         self: Arc<Self>,
-        layer: [u32; 5],
-        file_name: &str,
-    ) -> io::Result<()> {
-        // nginx will pass in a full path to some file.  Since we want
-        // to be at least somewhat security aware, we don't want this
-        // to just accept any arbitrary path. The path needs to
-        // actually live in what we know to be the upload path.
-        let path: PathBuf = tokio::fs::canonicalize(file_name).await?;
-        if path.parent().is_none() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "given file has no parent folder",
-            ));
-        }
-        let parent = path.parent().unwrap();
-        let upload_path = tokio::fs::canonicalize(&self.upload_path).await?;
+        layer: LayerName,
+        expected: usize,
+    ) -> io::Result<impl Stream<Item = io::Result<Bytes>> + Send> {
+        let name = layer_name_to_string(layer);
+        let stream = match self.get_layer(layer, None, false).await? {
+            Some((_, size, _, stream)) if size == expected => stream,
+            _ => {
+                return Err(io::Error::other(format!(
+                    "layer {name} changed while it was being bundled"
+                )))
+            }
+        };
+        let size = expected as u64;
+        let header = bundle::entry_header(&format!("{name}.larch"), size)?;
+
+        Ok(futures::stream::once(async { Ok(header) })
+            .chain(stream)
+            .chain(futures::stream::once(async move {
+                Ok(bundle::entry_padding(size))
+            })))
+    }
+
+    /// Stream a byte range of the archive of a layer, through the block
+    /// cache if there is one. Without a cache, `reader` must already be
+    /// positioned at the start of the range. The stream fails if the
+    /// file ends before the range does.
+    fn range_stream(
+        &self,
+        layer: LayerName,
+        reader: File,
+        range: Range<usize>,
+    ) -> impl Stream<Item = io::Result<Bytes>> + Send {
+        let len = range.end - range.start;
+        let stream = match &self.block_cache {
+            Some(cache) => Either::Left(cache.clone().stream_range(layer, reader, range)),
+            None => Either::Right(self.reader_stream(reader.take(len as u64))),
+        };
+
+        expect_length(Box::pin(stream), len)
+    }
+
+    /// The layers in the local cache, in no particular order. Layers
+    /// that are still being copied live in scratch and aren't listed.
+    pub async fn cached_layers(&self, limit: Option<usize>) -> io::Result<Vec<LayerName>> {
+        walk_layers(&self.local_path, limit).await
+    }
+
+    /// The metrics in Prometheus text format.
+    pub async fn render_metrics(&self) -> String {
+        let work_set = self.work_set.lock().await.len();
+        self.metrics.render(work_set, self.cache_budget_usage())
+    }
+
+    pub fn block_cache_stats(&self) -> Option<BlockCacheStats> {
+        self.block_cache.as_ref().map(|cache| cache.stats())
+    }
+
+    /// Like `get_layer`, but check that the archive header is
+    /// consistent with the file before streaming, and fail the stream
+    /// if it turns out shorter than announced. A completed download is
+    /// therefore a structurally valid layer.
+    pub async fn get_layer_validated(
+        self: Arc<Self>,
+        layer: LayerName,
+        with_parent: bool,
+    ) -> std::io::Result<
+        Option<(
+            usize,
+            Option<LayerName>,
+            impl Stream<Item = io::Result<Bytes>> + Send,
+        )>,
+    > {
+        let (size, mut reader) = match self.clone().get_layer_reader(layer).await? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+        let header = ArchiveHeader::parse_from_reader(&mut reader).await?;
+        let header_size = reader.stream_position().await? as usize;
+        validate_header(&header, header_size, size)?;
+
+        reader.seek(SeekFrom::Start(0)).await?;
+        let parent = if with_parent {
+            let parent = self.parent_hint(layer, size, &mut reader).await;
+            reader.seek(SeekFrom::Start(0)).await?;
+            parent
+        } else {
+            None
+        };
+        Ok(Some((
+            size,
+            parent,
+            expect_length(self.reader_stream(reader), size),
+        )))
+    }
+
+    /// Stream the gzipped dictionary files of a layer, framed like
+    /// `get_layer_files` responses. The bundle is built on first
+    /// request and kept next to the cached layer.
+    pub async fn get_dictionary_bundle(
+        self: Arc<Self>,
+        layer: LayerName,
+    ) -> io::Result<Option<(usize, impl Stream<Item = io::Result<Bytes>> + Send)>> {
+        let path = self.dictionary_bundle_path(layer);
+        if let Some((size, reader)) = self.file_reader(layer, &path).await? {
+            return Ok(Some((size, self.reader_stream(reader))));
+        }
+
+        if !self.clone().build_dictionary_bundle(layer, &path).await? {
+            return Ok(None);
+        }
+        match self.file_reader(layer, &path).await? {
+            Some((size, reader)) => Ok(Some((size, self.reader_stream(reader)))),
+            // evicted again already
+            None => Ok(None),
+        }
+    }
+
+    /// Write the dictionary bundle of a layer to `path`. Returns false
+    /// if the layer doesn't exist.
+    async fn build_dictionary_bundle(
+        self: Arc<Self>,
+        layer: LayerName,
+        path: &Path,
+    ) -> io::Result<bool> {
+        let (_sizes, stream) = match self
+            .clone()
+            .get_layer_files(layer, &DICTIONARY_FILES)
+            .await?
+        {
+            Some(result) => result,
+            None => return Ok(false),
+        };
+        let framed = stream
+            .map_ok(|(file, bytes)| {
+                let header = Bytes::from(frame_header(file, bytes.len()));
+                futures::stream::iter([Ok::<_, io::Error>(header), Ok(bytes)])
+            })
+            .try_flatten();
+        let mut encoder = GzipEncoder::new(StreamReader::new(Box::pin(framed)));
+
+        // Concurrent builds each get their own scratch file. They all
+        // produce the same bundle, so it doesn't matter which one wins.
+        let scratch = self.scratch_path.join(format!(
+            "{}.{}.dictionaries.gz",
+            layer_name_to_string(layer),
+            Uuid::new_v4()
+        ));
+        let mut result = async {
+            let mut file = File::create(&scratch).await?;
+            tokio::io::copy(&mut encoder, &mut file).await?;
+            file.sync_all().await
+        }
+        .await;
+        if result.is_ok() {
+            result = self.rename_into_dir(&scratch, path).await;
+        }
+        if result.is_err() {
+            if let Err(e) = tokio::fs::remove_file(&scratch).await {
+                if e.kind() != ErrorKind::NotFound {
+                    tracing::error!("could not remove scratch file: {e:?}");
+                }
+            }
+        }
+
+        result.map(|()| true)
+    }
+
+    /// Stream a layer followed by an index footer, so that readers can
+    /// find the files with a single read from the end. The footer holds
+    /// the absolute start and end offset of every kind of layer file as
+    /// big endian u64s, in `LayerFileEnum` order and zero for absent
+    /// files, followed by the length of the whole footer as a u64 and
+    /// the magic `INDEX_FOOTER_MAGIC`.
+    pub async fn get_layer_indexed(
+        self: Arc<Self>,
+        layer: LayerName,
+    ) -> std::io::Result<Option<(usize, impl Stream<Item = io::Result<Bytes>> + Send)>> {
+        let (size, mut reader) = match self.clone().get_layer_reader(layer).await? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+        // the footer is built from the header alone, so it has to agree
+        // with the file before it is promised to the client
+        let header = self.read_layer_header(layer, size, &mut reader).await?;
+        let offset = reader.stream_position().await?;
+
+        let mut footer = Vec::new();
+        for file in all_layer_files() {
+            let (start, end) = match header.range_for(file) {
+                Some(range) => (range.start as u64 + offset, range.end as u64 + offset),
+                None => (0, 0),
+            };
+            footer.extend_from_slice(&start.to_be_bytes());
+            footer.extend_from_slice(&end.to_be_bytes());
+        }
+        let footer_len = footer.len() + 8 + INDEX_FOOTER_MAGIC.len();
+        footer.extend_from_slice(&(footer_len as u64).to_be_bytes());
+        footer.extend_from_slice(INDEX_FOOTER_MAGIC);
+
+        reader.seek(SeekFrom::Start(0)).await?;
+        let stream = self
+            .reader_stream(reader)
+            .chain(futures::stream::once(
+                async move { Ok(Bytes::from(footer)) },
+            ));
+
+        Ok(Some((size + footer_len, stream)))
+    }
+
+    /// Stream the `index`th of `count` equally sized byte segments of a
+    /// layer. The stream fails if the layer ends before the segment does.
+    pub async fn get_layer_segment(
+        self: Arc<Self>,
+        layer: LayerName,
+        index: usize,
+        count: usize,
+    ) -> std::io::Result<Option<(usize, impl Stream<Item = io::Result<Bytes>> + Send)>> {
+        if let Some((size, mut reader)) = self.clone().get_layer_reader(layer).await? {
+            let start = (size as u64 * index as u64 / count as u64) as usize;
+            let end = (size as u64 * (index as u64 + 1) / count as u64) as usize;
+            reader.seek(SeekFrom::Start(start as u64)).await?;
+            let range = start..end;
+            let stream = self.reader_stream(reader.take(range.len() as u64));
+            Ok(Some((range.len(), expect_length(stream, range.len()))))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Store an uploaded layer in primary. `expected_size` is the size
+    /// the archive will have, if the client said so up front. A layer
+    /// that is in primary already with that size isn't uploaded again:
+    /// names are content hashes, so it can only be the same layer, and a
+    /// client retrying an upload whose response it missed shouldn't have
+    /// to send it all again. Without a size, there is nothing to tell a
+    /// complete layer from a truncated one, so the upload goes ahead.
+    pub async fn upload_layer(
+        self: Arc<Self>,
+        layer: LayerName,
+        stream: impl Stream<Item = Result<Bytes, hyper::Error>> + Unpin,
+        encoding: UploadEncoding,
+        expected_size: Option<u64>,
+    ) -> Result<UploadOutcome, Box<dyn Error>> {
+        if let Some(expected_size) = expected_size {
+            if self.primary_layer_matches(layer, expected_size).await? {
+                tracing::info!("layer already in primary, upload skipped");
+                return Ok(UploadOutcome::AlreadyExists);
+            }
+        }
+        let _permit = self.acquire_upload_permit().await?;
+        tracing::debug!("upload started");
+        let result = self.clone().receive_upload(layer, stream, encoding).await;
+        self.metrics.upload(result.is_ok());
+        match &result {
+            Ok(()) => tracing::info!("upload finished"),
+            Err(e) => tracing::warn!("upload failed: {e}"),
+        }
+
+        result.map(|()| UploadOutcome::Uploaded)
+    }
+
+    /// Wait for one of the upload slots, if their number is limited, for
+    /// at most the upload slot timeout.
+    async fn acquire_upload_permit(&self) -> Result<Option<SemaphorePermit<'_>>, TooManyUploads> {
+        match &self.upload_permits {
+            Some(permits) => {
+                match tokio::time::timeout(self.upload_slot_timeout, permits.acquire()).await {
+                    Ok(permit) => Ok(Some(permit.unwrap())),
+                    Err(_) => Err(TooManyUploads),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Whether a layer is in primary with the expected size.
+    async fn primary_layer_matches(
+        &self,
+        layer: LayerName,
+        expected_size: u64,
+    ) -> io::Result<bool> {
+        let path = match self.find_primary_layer_file(layer).await? {
+            Some(path) => path,
+            None => return Ok(false),
+        };
+        match tokio::fs::metadata(path).await {
+            Ok(metadata) => Ok(metadata.len() == expected_size),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn receive_upload(
+        self: Arc<Self>,
+        layer: LayerName,
+        stream: impl Stream<Item = Result<Bytes, hyper::Error>> + Unpin,
+        encoding: UploadEncoding,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut file = match TempFile::new_in(self.upload_temp_path()).await {
+            Ok(file) => file,
+            Err(async_tempfile::Error::Io(e)) => {
+                return Err(Box::new(self.check_upload_path_error(e)))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let written = Arc::new(AtomicUsize::new(0));
+        let _partial = self.clone().register_partial_layer(
+            layer,
+            file.file_path().to_path_buf(),
+            written.clone(),
+        );
+        let result = self
+            .write_upload(&mut file, &written, stream, encoding)
+            .await;
+        if let Err(e) = result {
+            // Dropping the temp file removes it as well, but silently
+            // gives up on failure. Partial uploads piling up in the
+            // upload dir should at least be noticed.
+            if let Err(e) = tokio::fs::remove_file(file.file_path()).await {
+                tracing::error!(
+                    "could not remove partial upload {:?}: {e:?}",
+                    file.file_path()
+                );
+            }
+            return Err(e);
+        }
+
+        self.move_uploaded_layer(layer, file.file_path()).await?;
+
+        Ok(())
+    }
+
+    /// Write an upload body to `file`, decoding it if needed.
+    async fn write_upload(
+        &self,
+        file: &mut TempFile,
+        written: &AtomicUsize,
+        stream: impl Stream<Item = Result<Bytes, hyper::Error>> + Unpin,
+        encoding: UploadEncoding,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let reader = StreamReader::new(stream.map(|chunk| chunk.map_err(io::Error::other)));
+        let mut reader = match encoding {
+            UploadEncoding::Identity => Either::Left(reader),
+            UploadEncoding::Gzip => Either::Right(GzipDecoder::new(reader)),
+        };
+        // The limit is checked against what is written rather than what
+        // is received, so that a small compressed body can't fill the disk.
+        let mut buf = vec![0; UPLOAD_BUFFER_SIZE];
+        let mut total: u64 = 0;
+        loop {
+            let len = match self.idle_timeout {
+                Some(idle) => tokio::time::timeout(idle, reader.read(&mut buf))
+                    .await
+                    .map_err(|_| UploadStalled { idle })??,
+                None => reader.read(&mut buf).await?,
+            };
+            if len == 0 {
+                break;
+            }
+            total += len as u64;
+            if let Some(limit) = self.max_upload_size {
+                if total > limit {
+                    return Err(Box::new(UploadTooLarge { limit }));
+                }
+            }
+            file.write_all(&buf[..len]).await?;
+            written.fetch_add(len, Ordering::Release);
+        }
+        file.flush().await?;
+
+        Ok(())
+    }
+
+    pub async fn start_upload_session(&self) -> io::Result<Uuid> {
+        self.upload_sessions
+            .start()
+            .await
+            .map_err(|e| self.check_upload_path_error(e))
+    }
+
+    pub async fn upload_session_offset(&self, id: Uuid) -> Result<u64, SessionError> {
+        self.upload_sessions.offset(id).await
+    }
+
+    pub async fn append_upload_session(
+        &self,
+        id: Uuid,
+        start: u64,
+        end: u64,
+        total: Option<u64>,
+        stream: impl Stream<Item = Result<Bytes, hyper::Error>> + Unpin,
+    ) -> Result<u64, SessionError> {
+        let _permit = self
+            .acquire_upload_permit()
+            .await
+            .map_err(|_| SessionError::TooManyUploads)?;
+        self.upload_sessions
+            .append(id, start, end, total, stream)
+            .await
+    }
+
+    /// Complete a resumable upload by moving it into place as the given layer.
+    pub async fn finish_upload_session(
+        self: Arc<Self>,
+        id: Uuid,
+        layer: LayerName,
+    ) -> Result<(), SessionError> {
+        let path = self.upload_sessions.finish(id).await?;
+        let result = self.move_uploaded_layer(layer, &path).await;
+        if result.is_err() {
+            // the session is gone, so don't leave its data behind either
+            let _ = tokio::fs::remove_file(&path).await;
+        }
+
+        Ok(result?)
+    }
+
+    /// Stage a single component file of a layer for `commit_staged_layer`.
+    pub async fn stage_layer_file(
+        &self,
+        layer: LayerName,
+        file: LayerFileEnum,
+        stream: impl Stream<Item = Result<Bytes, hyper::Error>> + Unpin,
+    ) -> Result<(), StageError> {
+        self.staging
+            .write_file(layer, file, stream, self.max_upload_size)
+            .await
+    }
+
+    /// Assemble the staged files of a layer into an archive and move it
+    /// into primary. Nothing is moved if required files are missing.
+    pub async fn commit_staged_layer(self: Arc<Self>, layer: LayerName) -> Result<(), StageError> {
+        let path = self.staging.assemble(layer).await?;
+        if let Err(e) = self.clone().move_uploaded_layer(layer, &path).await {
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(e.into());
+        }
+        if let Err(e) = self.staging.discard(layer).await {
+            tracing::error!("could not remove staged files: {e:?}");
+        }
+
+        Ok(())
+    }
+
+    async fn move_uploaded_layer(
+        self: Arc<Self>,
+        layer: LayerName,
+        file_path: impl AsRef<Path>,
+    ) -> io::Result<()> {
+        if self.durable_uploads {
+            // start out by making sure the file to be moved is
+            // actually fully on disk.
+            let file = tokio::fs::File::open(&file_path).await?;
+            file.sync_data().await?;
+        }
+
+        let destination_path = self.primary_layer_file_paths(layer).next().unwrap();
+        // renaming onto a directory fails with an error that doesn't say
+        // as much, and would leave the upload behind
+        if tokio::fs::metadata(&destination_path)
+            .await
+            .is_ok_and(|metadata| metadata.is_dir())
+        {
+            tracing::error!(
+                "upload destination {:?} is a directory, discarding upload",
+                destination_path
+            );
+            let _ = tokio::fs::remove_file(&file_path).await;
+            return Err(io::Error::other(DestinationIsDirectory(destination_path)));
+        }
+        // garbage in primary would only fail once someone reads it
+        if let Err(e) = validate_archive(file_path.as_ref()).await {
+            let _ = tokio::fs::remove_file(&file_path).await;
+            return Err(e);
+        }
+        tracing::debug!("moving {:?} into primary", file_path.as_ref());
+        self.rename_into_dir(file_path.as_ref(), &destination_path)
+            .await?;
+        if self.durable_uploads {
+            // the rename itself is only durable once the directory is
+            sync_dir(destination_path.parent().unwrap()).await?;
+        }
+        self.forget_header(layer);
+
+        self.spawn_cache_layer(layer).await;
+
+        Ok(())
+    }
+
+    pub async fn move_uploaded_outside_layer(
+        self: Arc<Self>,
+        layer: LayerName,
+        file_name: &str,
+    ) -> io::Result<()> {
+        // nginx will pass in a full path to some file.  Since we want
+        // to be at least somewhat security aware, we don't want this
+        // to just accept any arbitrary path. The path needs to
+        // actually live in what we know to be the upload path.
+        // Paths climbing out of a directory are turned away before
+        // anything is looked up, rather than trusting canonicalization
+        // alone to catch them.
+        if Path::new(file_name)
+            .components()
+            .any(|component| component == Component::ParentDir)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                BadUploadPath("given file path contains '..'"),
+            ));
+        }
+        let path: PathBuf = match tokio::fs::canonicalize(file_name).await {
+            Ok(path) => path,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    BadUploadPath("given file does not exist"),
+                ))
+            }
+            Err(e) => return Err(e),
+        };
+        if path.parent().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                BadUploadPath("given file has no parent folder"),
+            ));
+        }
+        let parent = path.parent().unwrap();
+        let upload_path = tokio::fs::canonicalize(&self.upload_path).await?;
         if parent != upload_path {
             return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "given file is not in upload folder",
+                io::ErrorKind::InvalidInput,
+                BadUploadPath("given file is not in upload folder"),
+            ));
+        }
+        self.move_uploaded_layer(layer, path).await
+    }
+
+    pub async fn spawn_cache_layer(self: Arc<Self>, layer: LayerName) {
+        // the copy runs in its own task, so there's no need to await it
+        drop(try_copy_layer(self, layer).await);
+    }
+
+    /// Cache a layer and wait until the copy into the local cache has
+    /// completed or failed. Concurrent calls for the same layer share a
+    /// single copy and all get its result.
+    pub async fn cache_layer_blocking(self: Arc<Self>, layer: LayerName) -> CopyResult {
+        try_copy_layer(self, layer).await.0.await
+    }
+
+    /// Start caching a layer unless it is cached already, without waiting
+    /// for the copy. Joining a copy that is already in flight counts as
+    /// queueing it.
+    pub async fn queue_cache_layer(self: Arc<Self>, layer: LayerName) -> io::Result<QueueOutcome> {
+        if self.local_layer_file_exists(layer).await? {
+            return Ok(QueueOutcome::AlreadyCached);
+        }
+        if self.find_primary_layer_file(layer).await?.is_none() {
+            return Ok(QueueOutcome::NotInPrimary);
+        }
+        self.spawn_cache_layer(layer).await;
+
+        Ok(QueueOutcome::Queued)
+    }
+
+    /// Start caching every given layer that isn't cached or being
+    /// cached already, without waiting for any of it. Layers that aren't
+    /// in primary are skipped. Returns how many copies were started.
+    pub async fn prefetch_layers(self: Arc<Self>, layers: Vec<LayerName>) -> io::Result<usize> {
+        let mut started = 0;
+        for layer in layers {
+            if self.local_layer_file_exists(layer).await? {
+                continue;
+            }
+            if self.find_primary_layer_file(layer).await?.is_none() {
+                continue;
+            }
+            let (_task, new) = try_copy_layer(self.clone(), layer).await;
+            if new {
+                self.warmups.requested(layer);
+                started += 1;
+            }
+        }
+
+        Ok(started)
+    }
+
+    /// Total size and number of layers in primary, along with how long
+    /// ago that was determined. Walking primary is expensive, so the
+    /// result is reused for a while.
+    pub async fn primary_usage(&self) -> io::Result<(PrimaryUsage, Duration)> {
+        // holding the lock during the walk makes concurrent requests
+        // wait for it rather than start walks of their own
+        let mut cached = self.primary_usage.lock().await;
+        if let Some((computed_at, usage)) = *cached {
+            if computed_at.elapsed() < self.primary_usage_ttl {
+                return Ok((usage, computed_at.elapsed()));
+            }
+        }
+
+        let mut usage = PrimaryUsage {
+            bytes: 0,
+            layers: 0,
+        };
+        for primary in &self.primary_paths {
+            let found = walk_usage(primary).await?;
+            usage.bytes += found.bytes;
+            usage.layers += found.layers;
+        }
+        *cached = Some((Instant::now(), usage));
+
+        Ok((usage, Duration::ZERO))
+    }
+
+    /// Produce each of the given layers as soon as it exists in primary,
+    /// checking every `interval`. The stream ends once all of them
+    /// have been found.
+    pub fn watch_primary_layers(
+        self: Arc<Self>,
+        layers: Vec<LayerName>,
+        interval: Duration,
+    ) -> impl Stream<Item = io::Result<LayerName>> {
+        futures::stream::unfold(
+            (self, layers, VecDeque::new(), false),
+            move |(manager, mut pending, mut found, mut polled)| async move {
+                loop {
+                    if let Some(layer) = found.pop_front() {
+                        return Some((Ok(layer), (manager, pending, found, polled)));
+                    }
+                    if pending.is_empty() {
+                        return None;
+                    }
+                    if polled {
+                        tokio::time::sleep(interval).await;
+                    }
+                    polled = true;
+
+                    let mut still_pending = Vec::with_capacity(pending.len());
+                    for layer in pending {
+                        match manager.find_primary_layer_file(layer).await {
+                            Ok(Some(_)) => found.push_back(layer),
+                            Ok(None) => still_pending.push(layer),
+                            Err(e) => return Some((Err(e), (manager, Vec::new(), found, polled))),
+                        }
+                    }
+                    pending = still_pending;
+                }
+            },
+        )
+    }
+
+    /// Report whether a layer is in the local cache. Returns None if
+    /// the layer doesn't exist at all.
+    pub async fn cache_status(&self, layer: LayerName) -> io::Result<Option<CacheStatus>> {
+        if self.work_set.lock().await.contains_key(&layer) {
+            Ok(Some(CacheStatus::Caching))
+        } else if self.local_layer_file_exists(layer).await? {
+            Ok(Some(CacheStatus::Cached))
+        } else if self.find_primary_layer_file(layer).await?.is_some() {
+            Ok(Some(CacheStatus::Uncached))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Forget everything cached about a layer, because its file in
+    /// primary was changed or removed.
+    pub async fn invalidate_layer(&self, layer: LayerName) {
+        self.forget_header(layer);
+        if let Some(budget) = &self.cache_budget {
+            budget.remove(layer);
+        }
+        self.remove_dictionary_bundle(layer).await;
+        let path = self.local_layer_file_path(layer);
+        let result = if self.serve_stale {
+            // keep it around in case primary becomes unreadable
+            tokio::fs::rename(&path, self.stale_layer_file_path(layer)).await
+        } else {
+            tokio::fs::remove_file(&path).await
+        };
+        match result {
+            Ok(()) => tracing::info!("invalidated cached layer {}", layer_name_to_string(layer)),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => tracing::error!("could not invalidate cached layer: {e:?}"),
+        }
+    }
+
+    /// Watch primary for layer files that are modified or removed, and
+    /// invalidate them. Watching stops when the returned watcher is
+    /// dropped.
+    pub fn watch_primary(self: Arc<Self>) -> notify::Result<RecommendedWatcher> {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    tracing::error!("watching primary failed: {e:?}");
+                    return;
+                }
+            };
+            if !matches!(event.kind, EventKind::Modify(_) | EventKind::Remove(_)) {
+                return;
+            }
+            for path in event.paths {
+                if let Some(layer) = layer_name_from_path(&path) {
+                    // the receiving end only goes away on shutdown
+                    let _ = sender.send(layer);
+                }
+            }
+        })?;
+        for primary in &self.primary_paths {
+            watcher.watch(primary, RecursiveMode::Recursive)?;
+        }
+
+        tokio::spawn(async move {
+            while let Some(layer) = receiver.recv().await {
+                self.invalidate_layer(layer).await;
+            }
+        });
+
+        Ok(watcher)
+    }
+
+    async fn remove_dictionary_bundle(&self, layer: LayerName) {
+        match tokio::fs::remove_file(self.dictionary_bundle_path(layer)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => tracing::error!("could not remove dictionary bundle: {e:?}"),
+        }
+    }
+
+    /// Remove a layer from the local cache. Layers that are currently
+    /// being cached are left alone. Returns the status the layer had,
+    /// so only if that is `Cached` was anything removed.
+    pub async fn evict_layer(&self, layer: LayerName) -> io::Result<CacheStatus> {
+        // hold on to the work set so no copy can start while we're removing
+        let work_set = self.work_set.lock().await;
+        if work_set.contains_key(&layer) {
+            return Ok(CacheStatus::Caching);
+        }
+
+        self.forget_header(layer);
+        self.remove_dictionary_bundle(layer).await;
+        match tokio::fs::remove_file(self.stale_layer_file_path(layer)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let result = match tokio::fs::remove_file(self.local_layer_file_path(layer)).await {
+            Ok(()) => Ok(CacheStatus::Cached),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(CacheStatus::Uncached),
+            Err(e) => return Err(e),
+        };
+        if let Some(budget) = &self.cache_budget {
+            budget.remove(layer);
+        }
+
+        result
+    }
+
+    /// Evict least recently used layers from the local cache until a
+    /// layer of `size` bytes fits in the budget, and reserve that room
+    /// for it. Returns false, reserving nothing, if the layer doesn't
+    /// fit even after evicting everything that can be.
+    async fn make_room(
+        &self,
+        budget: &CacheBudget,
+        layer: LayerName,
+        size: u64,
+    ) -> io::Result<bool> {
+        let _evicting = budget.evicting.lock().await;
+        if !budget.is_loaded() {
+            budget.load(walk_local_layers(&self.local_path).await?);
+        }
+
+        for victim in budget.by_recency() {
+            if budget.fits(size) {
+                break;
+            }
+            match self.evict_layer(victim).await {
+                // it's being copied, so it's the opposite of unused
+                Ok(CacheStatus::Caching) => {}
+                Ok(_) => tracing::info!(
+                    "evicted layer {} to make room in the local cache",
+                    layer_name_to_string(victim)
+                ),
+                Err(e) => tracing::error!("could not evict layer from local cache: {e:?}"),
+            }
+        }
+        if !budget.fits(size) {
+            return Ok(false);
+        }
+
+        budget.insert(layer, size);
+        Ok(true)
+    }
+
+    /// Bytes of layers in the local cache and the maximum, if the local
+    /// cache has a maximum size.
+    pub fn cache_budget_usage(&self) -> Option<(u64, u64)> {
+        self.cache_budget
+            .as_ref()
+            .map(|budget| (budget.used(), budget.max_bytes()))
+    }
+
+    async fn get_layer_header(
+        self: Arc<Self>,
+        layer: LayerName,
+    ) -> std::io::Result<Option<(ArchiveHeader, File)>> {
+        let (size, mut reader) = match self.clone().get_layer_reader(layer).await? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+        let header = self.read_layer_header(layer, size, &mut reader).await?;
+
+        Ok(Some((header, reader)))
+    }
+
+    /// The header of a layer, as parsed before or else from `reader`,
+    /// which must be at the start of the archive. Either way, `reader`
+    /// is left at the end of the header.
+    async fn read_layer_header(
+        &self,
+        layer: LayerName,
+        size: usize,
+        reader: &mut File,
+    ) -> io::Result<ArchiveHeader> {
+        let cached = self.headers.lock().unwrap().get(&layer).cloned();
+        // layers don't change, but a layer that was broken might have
+        // been replaced, and that would usually change its size.
+        if let Some(cached) = cached.filter(|cached| cached.size == size) {
+            timed(
+                layer,
+                "seek",
+                reader.seek(SeekFrom::Start(cached.header_len)),
+            )
+            .await?;
+            return Ok(cached.header);
+        }
+
+        let cached = parse_layer_header(layer, size, reader).await?;
+        let header = cached.header.clone();
+        self.headers.lock().unwrap().insert(layer, cached);
+
+        Ok(header)
+    }
+
+    /// Parse and cache the headers of all layers in the local cache, a
+    /// few at a time. Progress is tracked in `preparse_progress`.
+    pub async fn preparse_headers(self: Arc<Self>) {
+        let layers = match walk_layers(&self.local_path, None).await {
+            Ok(layers) => layers,
+            Err(e) => {
+                tracing::error!("could not list local layers to preparse: {e:?}");
+                self.preparse.finished.store(true, Ordering::Release);
+                return;
+            }
+        };
+        self.preparse.total.store(layers.len(), Ordering::Release);
+
+        let layers = futures::stream::iter(layers);
+        futures::StreamExt::for_each_concurrent(layers, PREPARSE_CONCURRENCY, |layer| {
+            let manager = self.clone();
+            async move {
+                if let Err(e) = manager.preparse_header(layer).await {
+                    tracing::warn!(
+                        "could not preparse header of layer {}: {e:?}",
+                        layer_name_to_string(layer)
+                    );
+                }
+                manager.preparse.done.fetch_add(1, Ordering::AcqRel);
+            }
+        })
+        .await;
+        self.preparse.finished.store(true, Ordering::Release);
+        tracing::info!(
+            "preparsed the headers of {} local layers",
+            self.preparse.done.load(Ordering::Acquire)
+        );
+    }
+
+    async fn preparse_header(&self, layer: LayerName) -> io::Result<()> {
+        // evicted since we listed it
+        let (size, mut reader) = match self.local_layer_file_reader(layer).await? {
+            Some(result) => result,
+            None => return Ok(()),
+        };
+        let cached = parse_layer_header(layer, size, &mut reader).await?;
+        self.headers.lock().unwrap().insert(layer, cached);
+
+        Ok(())
+    }
+
+    pub fn preparse_progress(&self) -> &PreparseProgress {
+        &self.preparse
+    }
+
+    fn forget_header(&self, layer: LayerName) {
+        self.headers.lock().unwrap().remove(&layer);
+        if let Some(cache) = &self.block_cache {
+            cache.invalidate(layer);
+        }
+    }
+
+    fn register_partial_layer(
+        self: Arc<Self>,
+        layer: LayerName,
+        path: PathBuf,
+        written: Arc<AtomicUsize>,
+    ) -> PartialLayerGuard {
+        self.partial_layers
+            .lock()
+            .unwrap()
+            .insert(layer, PartialLayer { path, written });
+
+        PartialLayerGuard {
+            manager: self,
+            layer,
+        }
+    }
+
+    /// Get the header of a layer that is still being written, along
+    /// with the number of bytes of it that are available so far.
+    async fn get_partial_layer_header(
+        &self,
+        layer: LayerName,
+    ) -> io::Result<Option<(ArchiveHeader, File, usize)>> {
+        let (path, written) = match self.partial_layers.lock().unwrap().get(&layer) {
+            Some(partial) => (partial.path.clone(), partial.written.clone()),
+            None => return Ok(None),
+        };
+        let mut reader = match File::open(&path).await {
+            Ok(reader) => reader,
+            // the upload just finished or failed
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        // The file only ever grows, so everything up to this point is
+        // guaranteed to be on disk for the rest of the request.
+        let available = written.load(Ordering::Acquire);
+        match ArchiveHeader::parse_from_reader(&mut reader).await {
+            Ok(header) => Ok(Some((header, reader, available))),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(not_yet_available()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Like `get_layer_header`, but fall back to layers that are still
+    /// being written. For those, the number of available bytes is
+    /// returned as well.
+    async fn get_available_layer_header(
+        self: Arc<Self>,
+        layer: LayerName,
+    ) -> io::Result<Option<(ArchiveHeader, File, Option<usize>)>> {
+        if let Some((header, reader)) = self.clone().get_layer_header(layer).await? {
+            Ok(Some((header, reader, None)))
+        } else if let Some((header, reader, available)) =
+            self.get_partial_layer_header(layer).await?
+        {
+            Ok(Some((header, reader, Some(available))))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The absolute byte range of every file present in a layer, in
+    /// archive order.
+    pub async fn get_layer_manifest(
+        self: Arc<Self>,
+        layer: LayerName,
+    ) -> io::Result<Option<Vec<(LayerFileEnum, Range<usize>)>>> {
+        let (header, mut reader) = match self.get_layer_header(layer).await? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+        let offset = reader.stream_position().await? as usize;
+
+        Ok(Some(
+            all_layer_files()
+                .filter_map(|file| {
+                    header
+                        .range_for(file)
+                        .map(|range| (file, range.start + offset..range.end + offset))
+                })
+                .collect(),
+        ))
+    }
+
+    /// The size of a layer, where it is read from, and the absolute
+    /// byte range of every file present in it, in archive order.
+    pub async fn get_layer_meta(
+        self: Arc<Self>,
+        layer: LayerName,
+    ) -> io::Result<Option<LayerMeta>> {
+        let (header, mut reader) = match self.clone().get_layer_header(layer).await? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+        let offset = reader.stream_position().await? as usize;
+        let size = timed(layer, "metadata", reader.metadata()).await?.len() as usize;
+        let files = all_layer_files()
+            .filter_map(|file| {
+                header
+                    .range_for(file)
+                    .map(|range| (file, range.start + offset..range.end + offset))
+            })
+            .collect();
+
+        Ok(Some(LayerMeta {
+            size,
+            local: self.local_layer_file_exists(layer).await?,
+            files,
+        }))
+    }
+
+    /// Read the name of the parent of a layer from its parent file.
+    /// Returns None for base layers and layers we don't have.
+    pub async fn get_layer_parent(
+        self: Arc<Self>,
+        layer: LayerName,
+    ) -> io::Result<Option<LayerName>> {
+        match self.lookup_layer_parent(layer).await? {
+            FileLookup::Found(parent) => Ok(Some(parent)),
+            FileLookup::LayerMissing | FileLookup::FileMissing => Ok(None),
+        }
+    }
+
+    async fn lookup_layer_parent(
+        self: Arc<Self>,
+        layer: LayerName,
+    ) -> io::Result<FileLookup<LayerName>> {
+        let (header, mut reader) = match self.get_layer_header(layer).await? {
+            Some(result) => result,
+            None => return Ok(FileLookup::LayerMissing),
+        };
+
+        read_layer_parent(&header, &mut reader).await
+    }
+
+    /// The parent of a layer, read from `reader`, which must be at the
+    /// start of its archive. This is only a hint for clients, so a
+    /// parent that can't be read is logged and left out.
+    async fn parent_hint(
+        &self,
+        layer: LayerName,
+        size: usize,
+        reader: &mut File,
+    ) -> Option<LayerName> {
+        let result = match self.read_layer_header(layer, size, reader).await {
+            Ok(header) => read_layer_parent(&header, reader).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(FileLookup::Found(parent)) => Some(parent),
+            Ok(_) => None,
+            Err(e) => {
+                tracing::warn!("could not read parent of layer: {e:?}");
+                None
+            }
+        }
+    }
+
+    /// The layer followed by all its ancestors, down to the base layer.
+    /// Returns None if the layer itself doesn't exist. A missing
+    /// ancestor, a cycle or a stack deeper than `MAX_CHAIN_DEPTH` is a
+    /// `BrokenChain` error.
+    pub async fn get_layer_chain(
+        self: Arc<Self>,
+        layer: LayerName,
+    ) -> io::Result<Option<Vec<LayerName>>> {
+        let mut chain = vec![layer];
+        let mut visited = HashSet::from([layer]);
+        loop {
+            let current = *chain.last().unwrap();
+            let parent = match self.clone().lookup_layer_parent(current).await? {
+                FileLookup::Found(parent) => parent,
+                FileLookup::FileMissing => return Ok(Some(chain)),
+                FileLookup::LayerMissing if chain.len() == 1 => return Ok(None),
+                FileLookup::LayerMissing => {
+                    return Err(broken_chain(format!(
+                        "ancestor {} is missing",
+                        layer_name_to_string(current)
+                    )))
+                }
+            };
+            if !visited.insert(parent) {
+                return Err(broken_chain(format!(
+                    "{} is its own ancestor",
+                    layer_name_to_string(parent)
+                )));
+            }
+            if chain.len() == MAX_CHAIN_DEPTH {
+                return Err(broken_chain(format!(
+                    "more than {MAX_CHAIN_DEPTH} layers deep"
+                )));
+            }
+            chain.push(parent);
+        }
+    }
+
+    /// The absolute byte range of a file within a layer. Files that are
+    /// present but empty have an empty range.
+    pub async fn get_layer_file_range(
+        self: Arc<Self>,
+        layer: LayerName,
+        file: LayerFileEnum,
+    ) -> std::io::Result<FileLookup<Range<usize>>> {
+        let (header, mut reader, available) = match self.get_available_layer_header(layer).await? {
+            Some(result) => result,
+            None => return Ok(FileLookup::LayerMissing),
+        };
+        let range = match header.range_for(file) {
+            Some(range) => range,
+            None => return Ok(FileLookup::FileMissing),
+        };
+
+        let offset = reader.stream_position().await? as usize;
+        let range = range.start + offset..range.end + offset;
+        if available.is_some_and(|available| range.end > available) {
+            return Err(not_yet_available());
+        }
+
+        Ok(FileLookup::Found(range))
+    }
+
+    /// Stream a file of a layer, or just the requested part of it.
+    /// Returns the part that is streamed, relative to the file, along
+    /// with the size of the whole file.
+    pub async fn get_layer_file(
+        self: Arc<Self>,
+        layer: LayerName,
+        file: LayerFileEnum,
+        requested: Option<ByteRange>,
+    ) -> std::io::Result<
+        FileLookup<(
+            Range<usize>,
+            usize,
+            impl Stream<Item = io::Result<Bytes>> + Send,
+        )>,
+    > {
+        let (header, mut reader, available) =
+            match self.clone().get_available_layer_header(layer).await? {
+                Some(result) => result,
+                None => return Ok(FileLookup::LayerMissing),
+            };
+        let range = match header.range_for(file) {
+            Some(range) => range,
+            None if file == LayerFileEnum::Rollup => {
+                let rollup = match self.read_rollup(layer).await? {
+                    Some(rollup) => rollup,
+                    None => return Ok(FileLookup::FileMissing),
+                };
+                let size = rollup.len();
+                let part = resolve_requested(requested, size)?;
+                let stream =
+                    futures::stream::once(futures::future::ready(Ok(rollup.slice(part.clone()))));
+                return Ok(FileLookup::Found((part, size, Either::Right(stream))));
+            }
+            None => return Ok(FileLookup::FileMissing),
+        };
+        let size = range.end - range.start;
+        let part = resolve_requested(requested, size)?;
+
+        let offset = reader.stream_position().await? as usize;
+        if available.is_some_and(|available| offset + range.start + part.end > available) {
+            return Err(not_yet_available());
+        }
+        if available.is_none() {
+            // The header was checked against the file when it was
+            // parsed, but the file may have been cut short since.
+            let actual = timed(layer, "metadata", reader.metadata()).await?.len() as usize;
+            if actual < offset + range.end {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    HeaderCorrupt {
+                        expected: offset + range.end,
+                        actual,
+                    },
+                ));
+            }
+        }
+        let start = offset + range.start + part.start;
+        let end = offset + range.start + part.end;
+        let stream = if available.is_none() {
+            // only complete layers go through the block cache, blocks of
+            // a layer that is still being written may be short
+            Either::Left(self.range_stream(layer, reader, start..end))
+        } else {
+            timed(layer, "seek", reader.seek(SeekFrom::Start(start as u64))).await?;
+            Either::Right(self.reader_stream(reader.take((end - start) as u64)))
+        };
+        Ok(FileLookup::Found((part, size, Either::Left(stream))))
+    }
+
+    /// The rollup file of a layer, if it has been rolled up. Unlike the
+    /// other layer files, this is not part of the archive but lives next
+    /// to it in a `{name}.rollup.hex` file, as a layer can be rolled up
+    /// long after it was written. For the same reason it is always read
+    /// from primary.
+    async fn read_rollup(&self, layer: LayerName) -> io::Result<Option<Bytes>> {
+        let path = match self.find_primary_layer_file(layer).await? {
+            Some(path) => path.with_extension("rollup.hex"),
+            None => return Ok(None),
+        };
+        match timed(layer, "read_rollup", tokio::fs::read(path)).await {
+            Ok(rollup) => Ok(Some(rollup.into())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Stream several files of a layer in archive order. Files that are
+    /// adjacent in the archive are fetched with a single contiguous
+    /// read rather than one seek per file. Files that are not present
+    /// in the layer are left out.
+    pub async fn get_layer_files(
+        self: Arc<Self>,
+        layer: LayerName,
+        files: &[LayerFileEnum],
+    ) -> std::io::Result<
+        Option<(
+            Vec<(LayerFileEnum, usize)>,
+            impl Stream<Item = io::Result<(LayerFileEnum, Bytes)>> + Send,
+        )>,
+    > {
+        let (header, mut reader) = match self.get_layer_header(layer).await? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+        let offset = reader.stream_position().await?;
+
+        let mut present: Vec<(LayerFileEnum, Range<usize>)> = files
+            .iter()
+            .filter_map(|file| header.range_for(*file).map(|range| (*file, range)))
+            .collect();
+        present.sort_by_key(|(file, range)| (range.start, *file));
+        present.dedup_by_key(|(file, _)| *file);
+
+        let sizes = present
+            .iter()
+            .map(|(file, range)| (*file, range.end - range.start))
+            .collect();
+
+        let mut runs: Vec<Vec<(LayerFileEnum, Range<usize>)>> = Vec::new();
+        for (file, range) in present {
+            match runs.last_mut() {
+                Some(run) if run.last().unwrap().1.end == range.start => run.push((file, range)),
+                _ => runs.push(vec![(file, range)]),
+            }
+        }
+
+        let stream = futures::stream::unfold(
+            (reader, runs.into_iter()),
+            move |(mut reader, mut runs)| async move {
+                let run = runs.next()?;
+                let result = read_run(&mut reader, offset, &run).await;
+                let items: Vec<io::Result<(LayerFileEnum, Bytes)>> = match result {
+                    Ok(items) => items.into_iter().map(Ok).collect(),
+                    Err(e) => vec![Err(e)],
+                };
+                Some((futures::stream::iter(items), (reader, runs)))
+            },
+        );
+
+        Ok(Some((sizes, futures::StreamExt::flatten(stream))))
+    }
+}
+
+impl LayerManager {
+    /// Reserve one of the limited slots for checksumming a layer.
+    pub fn try_acquire_checksum_permit(&self) -> Option<OwnedSemaphorePermit> {
+        self.checksum_permits.clone().try_acquire_owned().ok()
+    }
+
+    /// Stream the SHA-256 of every file present in the layer, in
+    /// archive order. Unlike most other operations this reads the
+    /// entire layer, so callers should hold a checksum permit.
+    pub async fn get_layer_checksums(
+        self: Arc<Self>,
+        layer: LayerName,
+    ) -> std::io::Result<
+        Option<impl Stream<Item = io::Result<(LayerFileEnum, Range<usize>, String)>> + Send>,
+    > {
+        let (header, mut reader) = match self.get_layer_header(layer).await? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+        let offset = reader.stream_position().await? as usize;
+
+        let mut present: Vec<(LayerFileEnum, Range<usize>)> = all_layer_files()
+            .filter_map(|file| header.range_for(file).map(|range| (file, range)))
+            .collect();
+        present.sort_by_key(|(file, range)| (range.start, *file));
+
+        let stream = futures::stream::unfold(
+            (reader, present.into_iter()),
+            move |(mut reader, mut files)| async move {
+                let (file, range) = files.next()?;
+                let range = range.start + offset..range.end + offset;
+                let result = hash_range(&mut reader, range.clone())
+                    .await
+                    .map(|digest| (file, range, digest));
+                Some((result, (reader, files)))
+            },
+        );
+
+        Ok(Some(stream))
+    }
+}
+
+/// Check that the ranges in a header are in order, don't overlap, and
+/// exactly cover the body of a file of the given size.
+fn validate_header(header: &ArchiveHeader, header_size: usize, size: usize) -> io::Result<()> {
+    let mut ranges: Vec<Range<usize>> = all_layer_files()
+        .filter_map(|file| header.range_for(file))
+        .collect();
+    ranges.sort_by_key(|range| range.start);
+
+    let mut end = 0;
+    for range in ranges {
+        if range.start < end || range.end < range.start {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "archive header contains inconsistent file ranges",
             ));
         }
-        self.move_uploaded_layer(layer, path).await
+        end = range.end;
     }
 
-    pub async fn spawn_cache_layer(self: Arc<Self>, layer: [u32; 5]) {
-        tokio::spawn(try_copy_layer(self, layer));
+    if header_size + end != size {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "archive header describes {} bytes but file is {size} bytes",
+                header_size + end
+            ),
+        ));
     }
 
-    async fn get_layer_header(
-        self: Arc<Self>,
-        layer: [u32; 5],
-    ) -> std::io::Result<Option<(ArchiveHeader, File)>> {
-        if let Some((_size, mut reader)) = self.get_layer_reader(layer).await? {
-            Ok(Some((
-                ArchiveHeader::parse_from_reader(&mut reader).await?,
-                reader,
-            )))
-        } else {
-            Ok(None)
+    Ok(())
+}
+
+/// Check that a file is a layer archive with a header that parses, is
+/// consistent with the size of the file, and lists every file a layer
+/// needs.
+async fn validate_archive(path: &Path) -> io::Result<()> {
+    let invalid = |reason: String| io::Error::new(ErrorKind::InvalidData, InvalidArchive(reason));
+    let mut file = File::open(path).await?;
+    let size = file.metadata().await?.len() as usize;
+    let header = match ArchiveHeader::parse_from_reader(&mut file).await {
+        Ok(header) => header,
+        Err(e) if matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::InvalidData) => {
+            return Err(invalid(format!("bad header: {e}")));
         }
+        Err(e) => return Err(e),
+    };
+    let header_len = file.stream_position().await? as usize;
+    if let Err(e) = validate_header(&header, header_len, size) {
+        return Err(invalid(e.to_string()));
+    }
+    let present: Vec<_> = all_layer_files()
+        .filter(|file| header.range_for(*file).is_some())
+        .collect();
+    let missing = missing_files(present.into_iter());
+    if !missing.is_empty() {
+        let missing: Vec<_> = missing
+            .into_iter()
+            .map(|file| file_enum_to_string(file).unwrap())
+            .collect();
+        return Err(invalid(format!(
+            "missing required files {}",
+            missing.join(", ")
+        )));
     }
 
-    pub async fn get_layer_file_range(
-        self: Arc<Self>,
-        layer: [u32; 5],
-        file: LayerFileEnum,
-    ) -> std::io::Result<Option<Range<usize>>> {
-        if let Some((header, mut reader)) = self.get_layer_header(layer).await? {
-            let offset = reader.stream_position().await? as usize;
-            Ok(header.range_for(file).map(|r| Range {
-                start: r.start + offset,
-                end: r.end + offset,
-            }))
-        } else {
-            Ok(None)
+    Ok(())
+}
+
+/// The part of a file of `size` bytes that was asked for, which is all
+/// of it if no range was requested.
+fn resolve_requested(requested: Option<ByteRange>, size: usize) -> io::Result<Range<usize>> {
+    match requested {
+        Some(requested) => requested
+            .resolve(size)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, RangeNotSatisfiable { size })),
+        None => Ok(0..size),
+    }
+}
+
+/// Fail the stream if it ends before `size` bytes were produced, so
+/// that a short read aborts the response rather than silently
+/// truncating it.
+fn expect_length<S: Stream<Item = io::Result<Bytes>> + Unpin>(
+    stream: S,
+    size: usize,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    futures::stream::unfold(
+        (stream, 0, false),
+        move |(mut stream, seen, failed)| async move {
+            if failed {
+                return None;
+            }
+            match stream.next().await {
+                Some(Ok(bytes)) => {
+                    let seen = seen + bytes.len();
+                    Some((Ok(bytes), (stream, seen, false)))
+                }
+                Some(Err(e)) => Some((Err(e), (stream, seen, true))),
+                None if seen < size => Some((
+                    Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        format!("expected {size} bytes but only read {seen}"),
+                    )),
+                    (stream, seen, true),
+                )),
+                None => None,
+            }
+        },
+    )
+}
+
+/// The number and total size of the layer files under a primary root.
+async fn walk_usage(root: &Path) -> io::Result<PrimaryUsage> {
+    let mut usage = PrimaryUsage {
+        bytes: 0,
+        layers: 0,
+    };
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if file_type.is_file() && layer_name_from_path(&entry.path()).is_some() {
+                usage.bytes += entry.metadata().await?.len();
+                usage.layers += 1;
+            }
         }
     }
 
-    pub async fn get_layer_file(
-        self: Arc<Self>,
-        layer: [u32; 5],
-        file: LayerFileEnum,
-    ) -> std::io::Result<Option<(usize, impl Stream<Item = io::Result<Bytes>> + Send)>> {
-        if let Some((header, mut reader)) = self.get_layer_header(layer).await? {
-            if let Some(range) = header.range_for(file) {
-                reader.seek(SeekFrom::Current(range.start as i64)).await?;
-                let size = range.end - range.start;
-                return Ok(Some((size, ReaderStream::new(reader.take(size as u64)))));
+    Ok(usage)
+}
+
+/// Every layer in the local cache, with its size and when it was last
+/// accessed, or modified on filesystems that don't keep access times.
+async fn walk_local_layers(root: &Path) -> io::Result<Vec<(LayerName, u64, SystemTime)>> {
+    let mut layers = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if let Some(layer) = layer_name_from_path(&entry.path()) {
+                let metadata = entry.metadata().await?;
+                let accessed = metadata.accessed().or_else(|_| metadata.modified())?;
+                layers.push((layer, metadata.len(), accessed));
             }
         }
+    }
 
-        Ok(None)
+    Ok(layers)
+}
+
+/// Run a filesystem operation on a layer, emitting how long it took as
+/// a debug event with target `fs`. This is what attributes a slow
+/// request to a particular syscall on a slow mount.
+async fn timed<T>(layer: LayerName, op: &'static str, operation: impl Future<Output = T>) -> T {
+    let start = Instant::now();
+    let result = operation.await;
+    tracing::debug!(
+        target: "fs",
+        layer = %layer_name_to_string(layer),
+        op,
+        elapsed_us = start.elapsed().as_micros() as u64,
+        "filesystem operation"
+    );
+
+    result
+}
+
+/// Parse the header of a layer, checking that the file is big enough
+/// for the files it describes.
+async fn parse_layer_header(
+    layer: LayerName,
+    size: usize,
+    reader: &mut File,
+) -> io::Result<CachedHeader> {
+    let header = match timed(
+        layer,
+        "parse_header",
+        ArchiveHeader::parse_from_reader(reader),
+    )
+    .await
+    {
+        Ok(header) => header,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            tracing::error!(
+                layer = %layer_name_to_string(layer),
+                size,
+                "layer header is truncated"
+            );
+            return Err(io::Error::new(e.kind(), HeaderTruncated { size }));
+        }
+        Err(e) => return Err(e),
+    };
+    // A truncated archive can still have an intact header, in
+    // which case every file would read back short or empty.
+    let header_len = reader.stream_position().await?;
+    let end = all_layer_files()
+        .filter_map(|file| header.range_for(file))
+        .map(|range| range.end)
+        .max()
+        .unwrap_or(0);
+    if header_len as usize + end > size {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            HeaderCorrupt {
+                expected: header_len as usize + end,
+                actual: size,
+            },
+        ));
+    }
+
+    Ok(CachedHeader {
+        header,
+        header_len,
+        size,
+    })
+}
+
+/// Read the name of the parent of a layer from its parent file.
+/// `reader` must be at the end of the header.
+async fn read_layer_parent(
+    header: &ArchiveHeader,
+    reader: &mut File,
+) -> io::Result<FileLookup<LayerName>> {
+    let range = match header.range_for(LayerFileEnum::Parent) {
+        Some(range) => range,
+        None => return Ok(FileLookup::FileMissing),
+    };
+
+    reader.seek(SeekFrom::Current(range.start as i64)).await?;
+    let mut name = String::new();
+    reader
+        .take((range.end - range.start) as u64)
+        .read_to_string(&mut name)
+        .await?;
+    parse_layer_name(name.trim())
+        .map(FileLookup::Found)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "invalid parent layer name"))
+}
+
+/// The names of all layers stored under a directory, or of the first
+/// `limit` found.
+async fn walk_layers(root: &Path, limit: Option<usize>) -> io::Result<Vec<LayerName>> {
+    let mut layers = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if limit.is_some_and(|limit| layers.len() >= limit) {
+                return Ok(layers);
+            }
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if let Some(layer) = layer_name_from_path(&entry.path()) {
+                layers.push(layer);
+            }
+        }
+    }
+
+    Ok(layers)
+}
+
+/// Get the layer name from the path of a `.larch` file.
+fn layer_name_from_path(path: &Path) -> Option<LayerName> {
+    if path.extension()? != "larch" {
+        return None;
+    }
+
+    parse_layer_name(path.file_stem()?.to_str()?)
+}
+
+/// Every file a layer archive may contain.
+pub fn all_layer_files() -> impl Iterator<Item = LayerFileEnum> {
+    (0..).map_while(LayerFileEnum::from_usize)
+}
+
+/// Compute the hex encoded SHA-256 of a byte range of a file.
+async fn hash_range(reader: &mut File, range: Range<usize>) -> io::Result<String> {
+    reader.seek(SeekFrom::Start(range.start as u64)).await?;
+    let mut remaining = range.end - range.start;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    while remaining > 0 {
+        let len = remaining.min(buf.len());
+        reader.read_exact(&mut buf[..len]).await?;
+        hasher.update(&buf[..len]);
+        remaining -= len;
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Read a run of adjacent files with a single read, then slice it up
+/// into the individual files.
+async fn read_run(
+    reader: &mut File,
+    offset: u64,
+    run: &[(LayerFileEnum, Range<usize>)],
+) -> io::Result<Vec<(LayerFileEnum, Bytes)>> {
+    let start = run.first().unwrap().1.start;
+    let end = run.last().unwrap().1.end;
+    reader.seek(SeekFrom::Start(offset + start as u64)).await?;
+    let mut buf = vec![0; end - start];
+    reader.read_exact(&mut buf).await?;
+    let buf = Bytes::from(buf);
+
+    Ok(run
+        .iter()
+        .map(|(file, range)| (*file, buf.slice(range.start - start..range.end - start)))
+        .collect())
+}
+
+/// The percentage of the filesystem containing `path` that is in use,
+/// counting blocks reserved for root as unavailable like df does.
+fn disk_usage_percent(path: &Path) -> io::Result<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let used = (stat.f_blocks - stat.f_bfree) as u128;
+    let total = used + stat.f_bavail as u128;
+    if total == 0 {
+        return Ok(0);
+    }
+
+    Ok((used * 100).div_ceil(total) as u8)
+}
+
+/// Derive a stream limit from the soft file descriptor limit. Every
+/// streamed response holds both a socket and a file open, so only half
+/// of the remaining descriptors are available to streams.
+fn default_max_open_streams() -> usize {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    let result = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+    if result != 0 || limit.rlim_cur == libc::RLIM_INFINITY {
+        return usize::MAX;
+    }
+
+    let soft_limit = limit.rlim_cur as usize;
+    (soft_limit.saturating_sub(FD_HEADROOM) / 2).max(1)
+}
+
+pub struct LayerMeta {
+    pub size: usize,
+    /// Whether the layer is in the local cache, rather than only in
+    /// primary
+    pub local: bool,
+    pub files: Vec<(LayerFileEnum, Range<usize>)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheStatus {
+    Cached,
+    Caching,
+    Uncached,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Cached => "cached",
+            Self::Caching => "caching",
+            Self::Uncached => "uncached",
+        }
+    }
+}
+
+/// Unregisters a partial layer when its upload ends, whether it
+/// succeeded or not.
+struct PartialLayerGuard {
+    manager: Arc<LayerManager>,
+    layer: LayerName,
+}
+
+impl Drop for PartialLayerGuard {
+    fn drop(&mut self) {
+        self.manager
+            .partial_layers
+            .lock()
+            .unwrap()
+            .remove(&self.layer);
+    }
+}
+
+/// Removes a layer from the work set when dropped. This makes sure that
+/// a copy task that panics doesn't leave its layer in the work set
+/// forever, which would prevent it from ever being cached again.
+struct WorkSetGuard {
+    manager: Arc<LayerManager>,
+    layer: LayerName,
+}
+
+impl Drop for WorkSetGuard {
+    fn drop(&mut self) {
+        if let Ok(mut work_set) = self.manager.work_set.try_lock() {
+            work_set.remove(&self.layer);
+        } else {
+            // We can't wait for the lock in drop, so leave it to a task.
+            let manager = self.manager.clone();
+            let layer = self.layer;
+            tokio::spawn(async move {
+                manager.work_set.lock().await.remove(&layer);
+            });
+        }
     }
 }
 
-async fn try_copy_layer(manager: Arc<LayerManager>, layer: [u32; 5]) {
+/// How a copy into the local cache ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CopyOutcome {
+    Copied,
+    AlreadyCached,
+    NotInPrimary,
+    /// The layer is larger than what can be freed up in the local cache.
+    NoRoom,
+}
+
+pub type CopyResult = Result<CopyOutcome, Arc<io::Error>>;
+
+/// What an upload did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UploadOutcome {
+    Uploaded,
+    /// The layer was in primary already, so the body wasn't read.
+    AlreadyExists,
+}
+
+/// What asking for a layer to be cached in the background did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueOutcome {
+    AlreadyCached,
+    Queued,
+    NotInPrimary,
+}
+
+/// A copy into the local cache that any number of requests can await.
+type CopyTask = Shared<BoxFuture<'static, CopyResult>>;
+
+/// Join the copy of a layer that is already in flight, or start a new
+/// one, in which case true is returned along with it. The copy runs in
+/// a task of its own, so it completes even if nobody awaits the result.
+/// The span is created here rather than inside the task, so that it is
+/// a child of the request that triggered the copy.
+async fn try_copy_layer(manager: Arc<LayerManager>, layer: LayerName) -> (CopyTask, bool) {
     // critical region - check that we're not already copying this layer
+    let mut work_set = manager.work_set.lock().await;
+    if let Some(task) = work_set.get(&layer) {
+        return (task.clone(), false);
+    }
+
+    let span = tracing::info_span!("copy_layer", layer = %layer_name_to_string(layer));
+    let task = copy_layer_if_uncached(manager.clone(), layer)
+        .instrument(span)
+        .boxed()
+        .shared();
+    work_set.insert(layer, task.clone());
+    tokio::spawn(task.clone());
+
+    (task, true)
+}
+
+async fn copy_layer_if_uncached(manager: Arc<LayerManager>, layer: LayerName) -> CopyResult {
+    // removes us from the work set again, however this ends
+    let _guard = WorkSetGuard {
+        manager: manager.clone(),
+        layer,
+    };
+
+    let result = copy_layer_unless_cached(&manager, layer).await;
+    match &result {
+        Ok(CopyOutcome::Copied) => tracing::info!("layer copied to local cache"),
+        Ok(CopyOutcome::AlreadyCached) => tracing::debug!("layer was already cached"),
+        Ok(CopyOutcome::NotInPrimary) => tracing::debug!("layer is not in primary"),
+        // logged where it happened
+        Ok(CopyOutcome::NoRoom) | Err(_) => {}
+    }
+    match &result {
+        Ok(CopyOutcome::Copied) | Ok(CopyOutcome::AlreadyCached) => manager.warmups.cached(layer),
+        Ok(CopyOutcome::NotInPrimary) | Ok(CopyOutcome::NoRoom) | Err(_) => {
+            manager.warmups.abandoned(layer)
+        }
+    }
+
+    result
+}
+
+async fn copy_layer_unless_cached(manager: &Arc<LayerManager>, layer: LayerName) -> CopyResult {
+    // final check to make sure that the file to be cached really doesn't exist
+    if manager.local_layer_file_exists(layer).await? {
+        return Ok(CopyOutcome::AlreadyCached);
+    }
+    // copy from whichever primary has it
+    let from = match manager.find_primary_layer_file(layer).await? {
+        Some(from) => from,
+        None => return Ok(CopyOutcome::NotInPrimary),
+    };
+
+    let budget = match &manager.cache_budget {
+        Some(budget) => budget,
+        None => return copy_layer_with_retries(manager, layer, &from).await,
+    };
+    let size = tokio::fs::metadata(&from).await?.len();
+    if !manager.make_room(budget, layer, size).await? {
+        tracing::warn!("layer doesn't fit in the local cache");
+        return Ok(CopyOutcome::NoRoom);
+    }
+    let result = copy_layer_with_retries(manager, layer, &from).await;
+    if !matches!(result, Ok(CopyOutcome::Copied)) {
+        // give back the room reserved for it
+        budget.remove(layer);
+    }
+
+    result
+}
+
+async fn copy_layer_with_retries(
+    manager: &Arc<LayerManager>,
+    layer: LayerName,
+    from: &Path,
+) -> CopyResult {
+    let mut backoff = manager.copy_retry_backoff;
+    let mut attempt = 0;
+    loop {
+        match copy_layer_to_local(manager, layer, from).await {
+            Ok(()) => return Ok(CopyOutcome::Copied),
+            Err(e) if attempt < manager.copy_retries && is_transient(&e) => {
+                attempt += 1;
+                tracing::warn!(
+                    "copying layer to local cache failed, retry {attempt} in {backoff:?}: {e:?}"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e)
+                if e.kind() == ErrorKind::NotFound
+                    && !tokio::fs::try_exists(from).await.unwrap_or(true) =>
+            {
+                tracing::warn!("layer disappeared from primary during copy");
+                return Ok(CopyOutcome::NotInPrimary);
+            }
+            Err(e) => {
+                tracing::error!("copying layer to local cache failed: {e:?}");
+                return Err(Arc::new(e));
+            }
+        }
+    }
+}
+
+/// Whether a failed copy may succeed when tried again. Copies mostly
+/// fail on flaky network mounts, so anything that isn't known to be
+/// permanent counts as transient.
+fn is_transient(e: &io::Error) -> bool {
+    !matches!(
+        e.kind(),
+        ErrorKind::NotFound | ErrorKind::PermissionDenied | ErrorKind::Unsupported
+    ) && e.raw_os_error() != Some(libc::ENOSPC)
+}
+
+/// Tell the kernel that a file is going to be read sequentially, so
+/// that it reads ahead more aggressively.
+fn advise_sequential(file: &File) {
+    #[cfg(target_os = "linux")]
     {
-        let mut work_set = manager.work_set.lock().await;
-        if work_set.contains(&layer) {
-            return;
+        use std::os::unix::io::AsRawFd;
+        let result =
+            unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL) };
+        if result != 0 {
+            tracing::warn!("posix_fadvise failed with {result}");
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = file;
+}
+
+async fn copy_to_scratch(
+    manager: &LayerManager,
+    layer: LayerName,
+    from: &Path,
+    to: &Path,
+) -> io::Result<()> {
+    match manager.primary_readahead {
+        Some(capacity) => {
+            let (_size, reader) = manager.file_reader(layer, from).await?.ok_or_else(|| {
+                io::Error::new(ErrorKind::NotFound, "layer disappeared from primary")
+            })?;
+            let mut reader = tokio::io::BufReader::with_capacity(capacity, reader);
+            let mut writer = File::create(to).await?;
+            timed(layer, "copy", tokio::io::copy_buf(&mut reader, &mut writer)).await?;
+            writer.flush().await
+        }
+        None => timed(layer, "copy", tokio::fs::copy(from, to))
+            .await
+            .map(|_| ()),
+    }
+}
+
+/// Check a copy of a layer against the checksum file next to the layer
+/// in primary. The checksum file may be in `sha256sum` format, anything
+/// after the hash is ignored. Layers without one pass.
+async fn verify_checksum(layer: LayerName, from: &Path, copy: &Path) -> io::Result<()> {
+    let checksum_path = from.with_extension("larch.sha256");
+    let expected = match tokio::fs::read_to_string(&checksum_path).await {
+        Ok(contents) => contents
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase(),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    let mut file = File::open(copy).await?;
+    let size = file.metadata().await?.len() as usize;
+    let actual = timed(layer, "checksum", hash_range(&mut file, 0..size)).await?;
+    if actual != expected {
+        tracing::error!(
+            layer = %layer_name_to_string(layer),
+            expected,
+            actual,
+            "copy of layer doesn't match its checksum, discarding it"
+        );
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            ChecksumMismatch { expected, actual },
+        ));
+    }
+
+    Ok(())
+}
+
+async fn sync_file(path: &Path) -> io::Result<()> {
+    File::open(path).await?.sync_all().await
+}
+
+/// Make the creation, removal or renaming of entries in a directory
+/// durable.
+async fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir).await?.sync_all().await
+}
+
+/// Check the configured directories before serving anything, so that
+/// a misconfiguration fails at startup rather than on some request
+/// later. Primaries must exist and be readable, and the first one
+/// writable, as uploads go there. Local, upload, scratch and the
+/// upload temp dir are created if missing and must be writable.
+pub async fn check_dirs(config: &Config) -> io::Result<()> {
+    for (index, primary) in config.primary.iter().enumerate() {
+        let path = Path::new(primary);
+        if let Err(e) = tokio::fs::read_dir(path).await {
+            return Err(dir_check_failed("primary", path, "is not readable", e));
+        }
+        if index == 0 {
+            check_writable("primary", path).await?;
         }
+    }
+    let dirs = [
+        ("local", &config.local),
+        ("upload", &config.upload),
+        ("scratch", &config.scratch),
+    ];
+    let upload_temp = config.upload_temp.iter().map(|dir| ("upload_temp", dir));
+    for (name, dir) in dirs.into_iter().chain(upload_temp) {
+        let path = Path::new(dir);
+        tokio::fs::create_dir_all(path)
+            .await
+            .map_err(|e| dir_check_failed(name, path, "can't be created", e))?;
+        check_writable(name, path).await?;
+    }
+
+    Ok(())
+}
+
+/// Check that files can be created in a directory by creating one.
+async fn check_writable(name: &str, dir: &Path) -> io::Result<()> {
+    let probe = dir.join(format!(".write-check.{}", Uuid::new_v4()));
+    tokio::fs::write(&probe, b"")
+        .await
+        .map_err(|e| dir_check_failed(name, dir, "is not writable", e))?;
+
+    tokio::fs::remove_file(&probe).await
+}
+
+fn dir_check_failed(name: &str, dir: &Path, problem: &str, e: io::Error) -> io::Error {
+    io::Error::new(
+        e.kind(),
+        format!("{name} directory {} {problem}: {e}", dir.display()),
+    )
+}
+
+/// Move a file to another device, for when a rename can't. The file is
+/// copied to a temporary file next to its destination first and then
+/// renamed, so the destination never holds a partial file.
+async fn move_across_devices(from: &Path, to: &Path) -> io::Result<()> {
+    let temp = to.with_file_name(format!(
+        ".{}.{}.tmp",
+        to.file_name().unwrap().to_string_lossy(),
+        Uuid::new_v4()
+    ));
+    let result = async {
+        tokio::fs::copy(from, &temp).await?;
+        sync_file(&temp).await?;
+        tokio::fs::rename(&temp, to).await
+    }
+    .await;
+    if let Err(e) = result {
+        let _ = tokio::fs::remove_file(&temp).await;
+        return Err(e);
+    }
+
+    tokio::fs::remove_file(from).await
+}
 
-        // final check to make sure that the file to be cached really doesn't exist
-        // If the existence check fails, we'll just take that as a sign that we cannot cache.
-        if manager.local_layer_file_exists(layer).await.unwrap_or(true) {
-            return;
+/// Hardlink a layer from primary into the local cache. Returns false
+/// without doing anything if the two live on different devices.
+async fn hardlink_layer_to_local(
+    manager: &LayerManager,
+    layer: LayerName,
+    from: &Path,
+) -> io::Result<bool> {
+    let dest = manager.local_layer_file_path(layer);
+    let parent = dest.parent().unwrap();
+    manager.ensure_dir(parent).await?;
+
+    let from_device = tokio::fs::metadata(from).await?.dev();
+    let dest_device = tokio::fs::metadata(parent).await?.dev();
+    if from_device != dest_device {
+        return Ok(false);
+    }
+
+    // Creating a link is atomic, so unlike a copy this doesn't need to
+    // go through the scratch dir.
+    if let Err(e) = timed(layer, "hard_link", tokio::fs::hard_link(from, &dest)).await {
+        if e.kind() == ErrorKind::NotFound {
+            // maybe the directory went away after all
+            manager.forget_dir(parent);
         }
+        return Err(e);
+    }
+    if manager.durable_cache {
+        sync_dir(parent).await?;
+    }
+
+    Ok(true)
+}
 
-        work_set.insert(layer);
+async fn copy_layer_to_local(
+    manager: &LayerManager,
+    layer: LayerName,
+    from: &Path,
+) -> io::Result<()> {
+    if manager.hardlink_cache {
+        match hardlink_layer_to_local(manager, layer, from).await {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(e) => tracing::warn!("hardlinking failed, copying instead: {e:?}"),
+        }
     }
 
-    let from = manager.primary_layer_file_path(layer);
     let to = manager.scratch_layer_file_path(layer);
-    let mut result = tokio::fs::copy(from, &to).await.map(|_| ());
-    if result.is_ok() {
+    let mut result = copy_to_scratch(manager, layer, from, &to).await;
+    #[cfg(test)]
+    if manager.panic_during_copy.load(Ordering::Relaxed) {
+        panic!("copy went wrong");
+    }
+    if result.is_ok() && manager.verify_cache_checksums {
+        // hardlinks share their bytes with primary, so only copies are
+        // checked
+        result = verify_checksum(layer, from, &to).await;
+    }
+    let mut discard = result.is_err();
+    if result.is_ok()
+        && manager
+            .local_layer_file_exists(layer)
+            .await
+            .unwrap_or(false)
+    {
+        // Someone sharing the local dir cached the layer while we were
+        // copying. Layers never change, so keep theirs and drop ours.
+        tracing::debug!("layer appeared in local cache during copy");
+        discard = true;
+    } else if result.is_ok() {
         // we managed to copy the file over to the scratch dir.
         // It is now time to move it to the destination.
         //
@@ -286,23 +3106,319 @@ async fn try_copy_layer(manager: Arc<LayerManager>, layer: [u32; 5]) {
         // to the destination, then a subsequent request could
         // accidentally return a partial file.
         //
-        // This obviously only works if the scratch and the local are
-        // on the same mount.
-        //
-        // this copy may actually fail, but we don't care.
+        // If the scratch and the local are on different mounts, the
+        // rename falls back to copying once more, into a temporary file
+        // next to the destination that is then renamed.
         let dest = manager.local_layer_file_path(layer);
-        if let Some(parent) = dest.parent() {
-            result = tokio::fs::create_dir_all(parent).await;
+        if manager.durable_cache {
+            result = sync_file(&to).await;
         }
         if result.is_ok() {
-            result = tokio::fs::rename(&to, dest).await;
+            result = manager.rename_into_dir(&to, &dest).await;
+        }
+        if result.is_ok() && manager.durable_cache {
+            result = sync_dir(dest.parent().unwrap()).await;
         }
+        discard = result.is_err();
     }
-    // remove from work set again
-    let mut work_set = manager.work_set.lock().await;
-    work_set.remove(&layer);
 
-    if let Err(e) = result {
-        eprintln!("Error: {e:?}");
+    if discard {
+        // Whatever went wrong, don't leave a (possibly partial)
+        // copy behind in the scratch dir.
+        if let Err(e) = tokio::fs::remove_file(&to).await {
+            if e.kind() != ErrorKind::NotFound {
+                tracing::error!("could not remove scratch file: {e:?}");
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+    use terminus_store::storage::string_to_name;
+
+    use super::*;
+    use crate::testutil::{archive, write_file, TestDirs, LAYER};
+
+    fn layer() -> [u32; 5] {
+        string_to_name(LAYER).unwrap()
+    }
+
+    fn is_empty_dir(dir: &Path) -> bool {
+        std::fs::read_dir(dir).unwrap().next().is_none()
+    }
+
+    #[tokio::test]
+    async fn failing_to_create_the_local_shard_fails_the_copy() {
+        let dirs = TestDirs::new();
+        let manager = dirs.manager(&[]);
+        let layer = layer();
+        write_file(
+            &manager.primary_layer_file_paths(layer).next().unwrap(),
+            b"layer",
+        );
+        // A dangling symlink where the shard directory goes can't be
+        // created as a directory, yet doesn't make the layer look cached.
+        let shard = manager.local_layer_file_path(layer);
+        std::os::unix::fs::symlink(dirs.path("missing"), shard.parent().unwrap()).unwrap();
+
+        let result = try_copy_layer(manager.clone(), layer).await.0.await;
+
+        assert!(result.is_err());
+        assert!(is_empty_dir(&dirs.path("scratch")));
+        assert!(manager.work_set.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_panicking_copy_leaves_the_work_set() {
+        let dirs = TestDirs::new();
+        let manager = dirs.manager(&[]);
+        let layer = layer();
+        write_file(
+            &manager.primary_layer_file_paths(layer).next().unwrap(),
+            b"layer",
+        );
+        manager.panic_during_copy.store(true, Ordering::Relaxed);
+
+        let (copy, _) = try_copy_layer(manager.clone(), layer).await;
+        assert!(tokio::spawn(copy).await.unwrap_err().is_panic());
+        assert!(manager.work_set.lock().await.is_empty());
+
+        // and the layer can be cached after all
+        manager.panic_during_copy.store(false, Ordering::Relaxed);
+        let result = try_copy_layer(manager.clone(), layer).await.0.await;
+        assert_eq!(result.unwrap(), CopyOutcome::Copied);
+    }
+
+    #[tokio::test]
+    async fn a_layer_cached_during_the_copy_is_kept() {
+        let dirs = TestDirs::new();
+        let manager = dirs.manager(&[]);
+        let layer = layer();
+        let from = manager.primary_layer_file_paths(layer).next().unwrap();
+        write_file(&from, b"ours");
+        // what someone sharing the local dir put there while we were
+        // copying
+        let local = manager.local_layer_file_path(layer);
+        write_file(&local, b"theirs");
+
+        copy_layer_to_local(&manager, layer, &from).await.unwrap();
+
+        assert_eq!(std::fs::read(&local).unwrap(), b"theirs");
+        assert!(is_empty_dir(&dirs.path("scratch")));
+    }
+
+    #[tokio::test]
+    async fn empty_layer_files_are_found() {
+        let dirs = TestDirs::new();
+        let manager = dirs.manager(&[]);
+        let layer = layer();
+        write_file(
+            &manager.local_layer_file_path(layer),
+            &archive(&[
+                (LayerFileEnum::NodeDictionaryBlocks, b"nodes"),
+                (LayerFileEnum::NegSubjects, b""),
+            ]),
+        );
+
+        let (part, size, stream) = match manager
+            .clone()
+            .get_layer_file(layer, LayerFileEnum::NegSubjects, None)
+            .await
+            .unwrap()
+        {
+            FileLookup::Found(found) => found,
+            _ => panic!("empty file not found"),
+        };
+        assert_eq!((part, size), (0..0, 0));
+        let contents: Vec<Bytes> = stream.try_collect().await.unwrap();
+        assert!(contents.iter().all(|chunk| chunk.is_empty()));
+
+        let range = match manager
+            .clone()
+            .get_layer_file_range(layer, LayerFileEnum::NegSubjects)
+            .await
+            .unwrap()
+        {
+            FileLookup::Found(range) => range,
+            _ => panic!("empty file not found"),
+        };
+        assert!(range.is_empty());
+    }
+
+    #[tokio::test]
+    async fn absent_layer_files_are_missing() {
+        let dirs = TestDirs::new();
+        let manager = dirs.manager(&[]);
+        let layer = layer();
+        write_file(
+            &manager.local_layer_file_path(layer),
+            &archive(&[(LayerFileEnum::NodeDictionaryBlocks, b"nodes")]),
+        );
+
+        let file = manager
+            .clone()
+            .get_layer_file(layer, LayerFileEnum::NegSubjects, None)
+            .await
+            .unwrap();
+        assert!(matches!(file, FileLookup::FileMissing));
+        let range = manager
+            .clone()
+            .get_layer_file_range(layer, LayerFileEnum::NegSubjects)
+            .await
+            .unwrap();
+        assert!(matches!(range, FileLookup::FileMissing));
+    }
+
+    #[tokio::test]
+    async fn an_upload_onto_a_directory_fails_cleanly() {
+        let dirs = TestDirs::new();
+        let manager = dirs.manager(&[]);
+        let layer = layer();
+        let upload = dirs.path("upload").join("upload.larch");
+        write_file(&upload, b"layer");
+        let destination = manager.primary_layer_file_paths(layer).next().unwrap();
+        std::fs::create_dir_all(&destination).unwrap();
+
+        let e = manager
+            .clone()
+            .move_uploaded_layer(layer, &upload)
+            .await
+            .unwrap_err();
+
+        assert!(e
+            .get_ref()
+            .is_some_and(|inner| inner.is::<DestinationIsDirectory>()));
+        assert!(!upload.exists());
+        assert!(destination.is_dir());
+    }
+
+    #[tokio::test]
+    async fn a_failing_upload_body_leaves_nothing_behind() {
+        let dirs = TestDirs::new();
+        let manager = dirs.manager(&[]);
+        let body = hyper::Body::wrap_stream(futures::stream::iter([
+            Ok(Bytes::from_static(b"the first part of a layer")),
+            Err(io::Error::new(
+                ErrorKind::ConnectionReset,
+                "client went away",
+            )),
+        ]));
+
+        let result = manager
+            .clone()
+            .upload_layer(layer(), body, UploadEncoding::Identity, None)
+            .await;
+
+        assert!(result.is_err());
+        assert!(is_empty_dir(&dirs.path("upload")));
+        assert!(manager
+            .find_primary_layer_file(layer())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn a_primary_read_starts_a_single_copy() {
+        let dirs = TestDirs::new();
+        let manager = dirs.manager(&[]);
+        let layer = layer();
+        write_file(
+            &manager.primary_layer_file_paths(layer).next().unwrap(),
+            b"layer",
+        );
+
+        let read = manager.clone().get_layer_reader(layer).await.unwrap();
+        assert!(read.is_some());
+        let copy = {
+            let work_set = manager.work_set.lock().await;
+            assert_eq!(work_set.len(), 1);
+            work_set[&layer].clone()
+        };
+        // a second request joins the copy that's already running
+        let (second, started) = try_copy_layer(manager.clone(), layer).await;
+        assert!(!started);
+        assert!(copy.ptr_eq(&second));
+
+        let (first, second) = tokio::join!(copy, manager.clone().cache_layer_blocking(layer));
+        // a second copy would have found the layer cached already
+        assert_eq!(first.unwrap(), CopyOutcome::Copied);
+        assert_eq!(second.unwrap(), CopyOutcome::Copied);
+        assert!(manager.work_set.lock().await.is_empty());
+        assert!(manager.local_layer_file_exists(layer).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn layer_files_are_sharded_by_the_prefix_length() {
+        for (prefix_len, shard) in [("2", "01"), ("4", "0123")] {
+            let dirs = TestDirs::new();
+            let manager = dirs.manager(&["--prefix-len", prefix_len]);
+            let layer = layer();
+            let file = format!("{LAYER}.larch");
+
+            let primary = dirs.path("primary").join(shard).join(&file);
+            let local = dirs.path("local").join(shard).join(&file);
+            assert_eq!(
+                manager.primary_layer_file_paths(layer).next(),
+                Some(primary.clone())
+            );
+            assert_eq!(manager.local_layer_file_path(layer), local);
+
+            write_file(&primary, b"layer");
+            assert_eq!(
+                manager.find_primary_layer_file(layer).await.unwrap(),
+                Some(primary)
+            );
+            let result = manager.clone().cache_layer_blocking(layer).await;
+            assert_eq!(result.unwrap(), CopyOutcome::Copied);
+            assert_eq!(std::fs::read(&local).unwrap(), b"layer");
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_layers_are_reported_as_broken() {
+        let dirs = TestDirs::new();
+        let manager = dirs.manager(&[]);
+        let layer = layer();
+        let archive = archive(&[
+            (LayerFileEnum::NodeDictionaryBlocks, b"nodes"),
+            (LayerFileEnum::NegSubjects, b"subjects"),
+        ]);
+        let path = manager.local_layer_file_path(layer);
+
+        // cut short in the middle of the files
+        write_file(&path, &archive[..archive.len() - 4]);
+        let e = match manager
+            .clone()
+            .get_layer_file(layer, LayerFileEnum::NegSubjects, None)
+            .await
+        {
+            Err(e) => e,
+            Ok(_) => panic!("truncated layer was served"),
+        };
+        assert!(e.get_ref().is_some_and(|inner| inner.is::<HeaderCorrupt>()));
+        let e = match manager.clone().get_layer_indexed(layer).await {
+            Err(e) => e,
+            Ok(_) => panic!("truncated layer was indexed"),
+        };
+        assert!(e.get_ref().is_some_and(|inner| inner.is::<HeaderCorrupt>()));
+
+        // cut short in the middle of the header
+        write_file(&path, &archive[..4]);
+        let e = match manager
+            .clone()
+            .get_layer_file(layer, LayerFileEnum::NegSubjects, None)
+            .await
+        {
+            Err(e) => e,
+            Ok(_) => panic!("truncated layer was served"),
+        };
+        assert!(e
+            .get_ref()
+            .is_some_and(|inner| inner.is::<HeaderTruncated>()));
     }
 }
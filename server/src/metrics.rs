@@ -0,0 +1,110 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Counters exposed at /metrics. These are bumped on the hot path, so
+/// they are plain atomics rather than anything that takes a lock.
+#[derive(Default)]
+pub struct Metrics {
+    local_hits: AtomicU64,
+    primary_fallbacks: AtomicU64,
+    uploads_succeeded: AtomicU64,
+    uploads_failed: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A layer was read from the local cache.
+    pub fn local_hit(&self) {
+        self.local_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A layer wasn't cached locally and was read from primary.
+    pub fn primary_fallback(&self) {
+        self.primary_fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn upload(&self, succeeded: bool) {
+        let counter = if succeeded {
+            &self.uploads_succeeded
+        } else {
+            &self.uploads_failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    /// `work_set` is the number of layers currently being copied, and
+    /// `local_cache` the bytes used in the local cache and its maximum,
+    /// if it has one.
+    pub fn render(&self, work_set: usize, local_cache: Option<(u64, u64)>) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str, value: u64| {
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} counter").unwrap();
+            writeln!(out, "{name} {value}").unwrap();
+        };
+        counter(
+            &mut out,
+            "layer_service_local_hits_total",
+            "Layers read from the local cache",
+            self.local_hits.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "layer_service_primary_fallbacks_total",
+            "Layers read from primary because they weren't cached",
+            self.primary_fallbacks.load(Ordering::Relaxed),
+        );
+        writeln!(
+            out,
+            "# HELP layer_service_uploads_total Layer uploads by outcome"
+        )
+        .unwrap();
+        writeln!(out, "# TYPE layer_service_uploads_total counter").unwrap();
+        writeln!(
+            out,
+            "layer_service_uploads_total{{outcome=\"success\"}} {}",
+            self.uploads_succeeded.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "layer_service_uploads_total{{outcome=\"failure\"}} {}",
+            self.uploads_failed.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "# HELP layer_service_work_set Layers being copied to the local cache"
+        )
+        .unwrap();
+        writeln!(out, "# TYPE layer_service_work_set gauge").unwrap();
+        writeln!(out, "layer_service_work_set {work_set}").unwrap();
+        if let Some((used, max)) = local_cache {
+            let gauge = |out: &mut String, name: &str, help: &str, value: u64| {
+                writeln!(out, "# HELP {name} {help}").unwrap();
+                writeln!(out, "# TYPE {name} gauge").unwrap();
+                writeln!(out, "{name} {value}").unwrap();
+            };
+            gauge(
+                &mut out,
+                "layer_service_local_cache_bytes",
+                "Bytes of layers in the local cache, including room reserved for copies",
+                used,
+            );
+            gauge(
+                &mut out,
+                "layer_service_local_cache_max_bytes",
+                "Maximum size of the local cache",
+                max,
+            );
+        }
+
+        out
+    }
+}
@@ -0,0 +1,54 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use regex::Regex;
+use terminus_store::storage::{name_to_string, string_to_name};
+
+/// Number of hex digits in a layer name. Supporting wider names should
+/// only take changing this and the conversions below.
+pub const NAME_HEX_LEN: usize = 40;
+
+/// A parsed layer name, 8 hex digits per element.
+pub type LayerName = [u32; NAME_HEX_LEN / 8];
+
+/// Whether a string is a layer name: exactly `NAME_HEX_LEN` lowercase
+/// hex digits.
+pub fn is_layer_name(name: &str) -> bool {
+    name.len() == NAME_HEX_LEN && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Whether parsed names are checked to format back to the same string
+/// in release builds too.
+static PARANOID: AtomicBool = AtomicBool::new(false);
+
+pub fn set_paranoid(paranoid: bool) {
+    PARANOID.store(paranoid, Ordering::Relaxed);
+}
+
+pub fn parse_layer_name(name: &str) -> Option<LayerName> {
+    if !is_layer_name(name) {
+        return None;
+    }
+    let parsed = string_to_name(name).ok()?;
+
+    // Paths are built from the formatted name, so a name that doesn't
+    // round trip would make us read some other layer's file.
+    let round_trips = || name_to_string(parsed) == name;
+    debug_assert!(round_trips(), "layer name {name} does not round trip");
+    if PARANOID.load(Ordering::Relaxed) && !round_trips() {
+        tracing::error!("layer name {name} does not round trip");
+        return None;
+    }
+
+    Some(parsed)
+}
+
+pub fn layer_name_to_string(name: LayerName) -> String {
+    name_to_string(name)
+}
+
+/// Compile a regex in which every `{name}` is a capture group matching
+/// a layer name.
+pub fn name_regex(pattern: &str) -> Regex {
+    let name = format!("([0-9a-f]{{{NAME_HEX_LEN}}})");
+    Regex::new(&pattern.replace("{name}", &name)).unwrap()
+}
@@ -1,18 +1,53 @@
+use async_compression::tokio::bufread::{GzipEncoder, ZstdEncoder};
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
 use hyper::{
+    server::conn::{AddrStream, Http},
     service::{make_service_fn, service_fn},
     Body, Method, Request, Response, Server, Uri,
 };
 use lazy_static::lazy_static;
+use num_traits::FromPrimitive;
 use regex::Regex;
 use std::{
+    collections::HashMap,
     convert::Infallible,
     net::{IpAddr, Ipv6Addr, SocketAddr},
-    path::PathBuf,
-    sync::Arc,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
-use terminus_store::storage::{consts::LayerFileEnum, string_to_name};
+use terminus_store::storage::consts::LayerFileEnum;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::{
+    either::Either,
+    io::{ReaderStream, StreamReader},
+};
+use tracing::Instrument;
+use uuid::Uuid;
 
-use super::manager::LayerManager;
+use super::acl::Acl;
+use super::alias::load_alias_map;
+use super::config::Config;
+use super::cors::Cors;
+use super::manager::{
+    all_layer_files, check_dirs, BadUploadPath, BrokenChain, ByteRange, CacheStatus, CopyOutcome,
+    DestinationIsDirectory, FileLookup, HeaderCorrupt, HeaderTruncated, InvalidArchive,
+    LayerManager, NotYetAvailable, QueueOutcome, RangeNotSatisfiable, TooManyUploads,
+    UploadEncoding, UploadOutcome, UploadPathUnwritable, UploadStalled, UploadTooLarge,
+};
+use super::name::{
+    self, is_layer_name, layer_name_to_string, name_regex, parse_layer_name, LayerName,
+};
+use super::session::SessionError;
+use super::stage::StageError;
+use super::telemetry;
+use super::tls;
+use super::warmup::WARMUP_BUCKETS_MS;
 
 enum InvalidReason {
     BadMethod,
@@ -24,19 +59,80 @@ impl InvalidReason {
             Self::BadMethod => 400,
         }
     }
-    fn msg(&self) -> Body {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::BadMethod => "bad_method",
+        }
+    }
+    fn msg(&self) -> &'static str {
         match self {
-            Self::BadMethod => "invalid method".into(),
+            Self::BadMethod => "invalid method",
         }
     }
 }
 
 enum ResourceSpec {
-    Cache([u32; 5]),
-    Layer([u32; 5]),
-    UploadFile([u32; 5]),
-    LayerFile([u32; 5], LayerFileEnum),
-    LayerFileRange([u32; 5], LayerFileEnum),
+    Cache(LayerName),
+    Layer(LayerName),
+    /// `POST /upload/{name}` moves a file that was already written to the
+    /// upload dir, by nginx for instance, into primary. The path of the
+    /// file is given in the `upload-path` header and must be directly in
+    /// the upload dir.
+    UploadFile(LayerName),
+    LayerFile(LayerName, LayerFileEnum),
+    LayerFileRange(LayerName, LayerFileEnum),
+    LayerFiles(LayerName, Vec<LayerFileEnum>),
+    LayerSegment(LayerName, usize, usize),
+    LayerChecksums(LayerName),
+    Stats,
+    Metrics,
+    Readyz,
+    Version,
+    FileNames,
+    AdminConfig,
+    AdminMounts,
+    AdminPrimaryUsage,
+    RecentCache,
+    CachedLayers(Option<usize>),
+    DictionaryBundle(LayerName),
+    UploadStart,
+    UploadSession(Uuid),
+    UploadSessionFinish(Uuid),
+    LayerEvents(Vec<LayerName>),
+    Bench(u64),
+    ManifestDiff(LayerName, LayerName),
+    LayerIndexed(LayerName),
+    StageFile(LayerName, LayerFileEnum),
+    StageCommit(LayerName),
+    FileRanges(LayerName),
+    LayerChain(LayerName),
+    LayerMeta(LayerName),
+    Prefetch,
+    Bundle,
+}
+
+impl ResourceSpec {
+    /// The layer this resource is about, if any.
+    fn layer(&self) -> Option<LayerName> {
+        match self {
+            Self::Cache(layer)
+            | Self::Layer(layer)
+            | Self::UploadFile(layer)
+            | Self::LayerFile(layer, _)
+            | Self::LayerFileRange(layer, _)
+            | Self::LayerFiles(layer, _)
+            | Self::LayerSegment(layer, _, _)
+            | Self::LayerChecksums(layer)
+            | Self::LayerIndexed(layer)
+            | Self::DictionaryBundle(layer)
+            | Self::StageFile(layer, _)
+            | Self::StageCommit(layer)
+            | Self::FileRanges(layer)
+            | Self::LayerChain(layer)
+            | Self::LayerMeta(layer) => Some(*layer),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -44,33 +140,148 @@ enum SpecParseError {
     UnknownPath,
     BadLayerName,
     UnknownLayerFile,
+    MissingQueryParameter,
+    BadSegment,
+    BadSession,
+    BadLimit,
 }
 
+impl SpecParseError {
+    fn status(&self) -> u16 {
+        match self {
+            Self::UnknownPath => 404,
+            // everything else is a path we know, asked for wrongly
+            Self::BadLayerName
+            | Self::UnknownLayerFile
+            | Self::MissingQueryParameter
+            | Self::BadSegment
+            | Self::BadSession
+            | Self::BadLimit => 400,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            Self::UnknownPath => "unknown_path",
+            Self::BadLayerName => "bad_layer_name",
+            Self::UnknownLayerFile => "unknown_file",
+            Self::MissingQueryParameter => "missing_query_parameter",
+            Self::BadSegment => "bad_segment",
+            Self::BadSession => "bad_session",
+            Self::BadLimit => "bad_limit",
+        }
+    }
+
+    fn msg(&self) -> &'static str {
+        match self {
+            Self::UnknownPath => "unknown path",
+            Self::BadLayerName => "invalid layer name",
+            Self::UnknownLayerFile => "unknown layer file",
+            Self::MissingQueryParameter => "missing query parameter",
+            Self::BadSegment => "invalid segment",
+            Self::BadSession => "invalid upload session",
+            Self::BadLimit => "invalid limit",
+        }
+    }
+
+    fn response(&self) -> Response<Body> {
+        error_response(self.status(), self.code(), self.msg())
+    }
+}
+
+/// Upper bound on the number of segments a layer can be split into.
+const MAX_SEGMENTS: usize = 1024;
+
+/// Largest body accepted by POST /ranges. There are only so many layer
+/// files to ask for.
+const MAX_RANGES_BODY: usize = 64 * 1024;
+
+/// Largest body accepted by POST /prefetch and /bundle, which is room
+/// for a few thousand layer names.
+const MAX_LAYER_NAMES_BODY: usize = 256 * 1024;
+
+/// How often primary is checked for layers that event streams wait for.
+const LAYER_EVENTS_INTERVAL: Duration = Duration::from_secs(1);
+
 fn uri_to_spec(uri: &Uri) -> Result<ResourceSpec, SpecParseError> {
     lazy_static! {
-        static ref RE_CACHE: Regex = Regex::new(r"^/cache/([0-9a-f]{40})$").unwrap();
-        static ref RE_LAYER: Regex = Regex::new(r"^/layer/([0-9a-f]{40})$").unwrap();
-        static ref RE_UPLOAD: Regex = Regex::new(r"^/upload/([0-9a-f]{40})$").unwrap();
-        static ref RE_FILE: Regex = Regex::new(r"^/file/([0-9a-f]{40})/(\w+)$").unwrap();
-        static ref RE_FILE_RANGE: Regex = Regex::new(r"^/range/([0-9a-f]{40})/(\w+)$").unwrap();
+        static ref RE_CACHE: Regex = name_regex(r"^/cache/{name}$");
+        static ref RE_LAYER: Regex = name_regex(r"^/layer/{name}$");
+        static ref RE_UPLOAD: Regex = name_regex(r"^/upload/{name}$");
+        static ref RE_FILE: Regex = name_regex(r"^/file/{name}/(\w+)$");
+        static ref RE_FILE_RANGE: Regex = name_regex(r"^/range/{name}/(\w+)$");
+        static ref RE_BATCH: Regex = name_regex(r"^/batch/{name}$");
+        static ref RE_CHECKSUMS: Regex = name_regex(r"^/layers/{name}/checksums$");
+        static ref RE_SEGMENT: Regex = name_regex(r"^/layer/{name}/segment/(\d+)/(\d+)$");
+        static ref RE_INDEXED: Regex = name_regex(r"^/layer/{name}/indexed$");
+        static ref RE_BUNDLE: Regex = name_regex(r"^/bundle/{name}/dictionaries$");
+        static ref RE_STAGE: Regex = name_regex(r"^/stage/{name}/(\w+)$");
+        static ref RE_RANGES: Regex = name_regex(r"^/ranges/{name}$");
+        static ref RE_CHAIN: Regex = name_regex(r"^/chain/{name}$");
+        static ref RE_META: Regex = name_regex(r"^/meta/{name}$");
+        static ref RE_DIFF: Regex = name_regex(r"^/diff/{name}/{name}/manifest$");
+        static ref RE_BENCH: Regex = Regex::new(r"^/bench/(\d+)$").unwrap();
+        static ref RE_LAYER_PATH: Regex =
+            Regex::new(r"^/(?:cache|layer|upload|file|range|batch|layers)/([^/]*)").unwrap();
+        static ref RE_SESSION: Regex = Regex::new(r"^/upload/([0-9a-f-]{36})$").unwrap();
+        static ref RE_SESSION_FINISH: Regex =
+            Regex::new(r"^/upload/([0-9a-f-]{36})/finish$").unwrap();
     }
     let path = uri.path();
 
-    if let Some(captures) = RE_CACHE.captures(path) {
+    if path == "/stats" {
+        Ok(ResourceSpec::Stats)
+    } else if path == "/metrics" {
+        Ok(ResourceSpec::Metrics)
+    } else if path == "/readyz" {
+        Ok(ResourceSpec::Readyz)
+    } else if path == "/version" {
+        Ok(ResourceSpec::Version)
+    } else if path == "/files" {
+        Ok(ResourceSpec::FileNames)
+    } else if path == "/admin/config" {
+        Ok(ResourceSpec::AdminConfig)
+    } else if path == "/admin/mounts" {
+        Ok(ResourceSpec::AdminMounts)
+    } else if path == "/admin/primary-usage" {
+        Ok(ResourceSpec::AdminPrimaryUsage)
+    } else if path == "/cache" {
+        let limit = match query_param(uri, "limit") {
+            Some(limit) => Some(limit.parse().map_err(|_| SpecParseError::BadLimit)?),
+            None => None,
+        };
+        Ok(ResourceSpec::CachedLayers(limit))
+    } else if path == "/prefetch" {
+        Ok(ResourceSpec::Prefetch)
+    } else if path == "/bundle" {
+        Ok(ResourceSpec::Bundle)
+    } else if path == "/recent-cache" {
+        Ok(ResourceSpec::RecentCache)
+    } else if path == "/upload/start" {
+        Ok(ResourceSpec::UploadStart)
+    } else if path == "/events/layers" {
+        let names = query_param(uri, "names").ok_or(SpecParseError::MissingQueryParameter)?;
+        let layers = names
+            .split(',')
+            .map(|name| parse_layer_name(name).ok_or(SpecParseError::BadLayerName))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ResourceSpec::LayerEvents(layers))
+    } else if let Some(captures) = RE_CACHE.captures(path) {
         let name = captures.get(1).unwrap();
         Ok(ResourceSpec::Cache(
-            string_to_name(name.as_str()).map_err(|_e| SpecParseError::BadLayerName)?,
+            parse_layer_name(name.as_str()).ok_or(SpecParseError::BadLayerName)?,
         ))
     } else if let Some(captures) = RE_LAYER.captures(path) {
         let name = captures.get(1).unwrap();
         Ok(ResourceSpec::Layer(
-            string_to_name(name.as_str()).map_err(|_e| SpecParseError::BadLayerName)?,
+            parse_layer_name(name.as_str()).ok_or(SpecParseError::BadLayerName)?,
         ))
     } else if let Some(captures) = RE_FILE.captures(path) {
         let layer_name = captures.get(1).unwrap();
         let file_name = captures.get(2).unwrap();
         let layer_name =
-            string_to_name(layer_name.as_str()).map_err(|_e| SpecParseError::BadLayerName)?;
+            parse_layer_name(layer_name.as_str()).ok_or(SpecParseError::BadLayerName)?;
         if let Some(file) = file_name_to_enum(file_name.as_str()) {
             Ok(ResourceSpec::LayerFile(layer_name, file))
         } else {
@@ -80,7 +291,7 @@ fn uri_to_spec(uri: &Uri) -> Result<ResourceSpec, SpecParseError> {
         let layer_name = captures.get(1).unwrap();
         let file_name = captures.get(2).unwrap();
         let layer_name =
-            string_to_name(layer_name.as_str()).map_err(|_e| SpecParseError::BadLayerName)?;
+            parse_layer_name(layer_name.as_str()).ok_or(SpecParseError::BadLayerName)?;
         if let Some(file) = file_name_to_enum(file_name.as_str()) {
             Ok(ResourceSpec::LayerFileRange(layer_name, file))
         } else {
@@ -89,160 +300,1589 @@ fn uri_to_spec(uri: &Uri) -> Result<ResourceSpec, SpecParseError> {
     } else if let Some(captures) = RE_UPLOAD.captures(path) {
         let layer_name = captures.get(1).unwrap();
         let layer_name =
-            string_to_name(layer_name.as_str()).map_err(|_e| SpecParseError::BadLayerName)?;
+            parse_layer_name(layer_name.as_str()).ok_or(SpecParseError::BadLayerName)?;
 
         Ok(ResourceSpec::UploadFile(layer_name))
+    } else if let Some(captures) = RE_BATCH.captures(path) {
+        let layer_name = captures.get(1).unwrap();
+        let layer_name =
+            parse_layer_name(layer_name.as_str()).ok_or(SpecParseError::BadLayerName)?;
+        let files = query_param(uri, "files").ok_or(SpecParseError::MissingQueryParameter)?;
+        let files = files
+            .split(',')
+            .map(|file| file_name_to_enum(file).ok_or(SpecParseError::UnknownLayerFile))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ResourceSpec::LayerFiles(layer_name, files))
+    } else if let Some(captures) = RE_SEGMENT.captures(path) {
+        let layer_name = captures.get(1).unwrap();
+        let layer_name =
+            parse_layer_name(layer_name.as_str()).ok_or(SpecParseError::BadLayerName)?;
+        let index: usize = captures[2]
+            .parse()
+            .map_err(|_e| SpecParseError::BadSegment)?;
+        let count: usize = captures[3]
+            .parse()
+            .map_err(|_e| SpecParseError::BadSegment)?;
+        if count == 0 || count > MAX_SEGMENTS || index >= count {
+            return Err(SpecParseError::BadSegment);
+        }
+
+        Ok(ResourceSpec::LayerSegment(layer_name, index, count))
+    } else if let Some(captures) = RE_CHECKSUMS.captures(path) {
+        let layer_name = captures.get(1).unwrap();
+        let layer_name =
+            parse_layer_name(layer_name.as_str()).ok_or(SpecParseError::BadLayerName)?;
+
+        Ok(ResourceSpec::LayerChecksums(layer_name))
+    } else if let Some(captures) = RE_SESSION.captures(path) {
+        let session = Uuid::parse_str(&captures[1]).map_err(|_e| SpecParseError::BadSession)?;
+
+        Ok(ResourceSpec::UploadSession(session))
+    } else if let Some(captures) = RE_SESSION_FINISH.captures(path) {
+        let session = Uuid::parse_str(&captures[1]).map_err(|_e| SpecParseError::BadSession)?;
+
+        Ok(ResourceSpec::UploadSessionFinish(session))
+    } else if let Some(captures) = RE_INDEXED.captures(path) {
+        let layer_name = parse_layer_name(&captures[1]).ok_or(SpecParseError::BadLayerName)?;
+
+        Ok(ResourceSpec::LayerIndexed(layer_name))
+    } else if let Some(captures) = RE_BUNDLE.captures(path) {
+        let layer_name = parse_layer_name(&captures[1]).ok_or(SpecParseError::BadLayerName)?;
+
+        Ok(ResourceSpec::DictionaryBundle(layer_name))
+    } else if let Some(captures) = RE_STAGE.captures(path) {
+        let layer_name = parse_layer_name(&captures[1]).ok_or(SpecParseError::BadLayerName)?;
+        if &captures[2] == "commit" {
+            return Ok(ResourceSpec::StageCommit(layer_name));
+        }
+        let file = file_name_to_enum(&captures[2]).ok_or(SpecParseError::UnknownLayerFile)?;
+
+        Ok(ResourceSpec::StageFile(layer_name, file))
+    } else if let Some(captures) = RE_RANGES.captures(path) {
+        let layer_name = parse_layer_name(&captures[1]).ok_or(SpecParseError::BadLayerName)?;
+
+        Ok(ResourceSpec::FileRanges(layer_name))
+    } else if let Some(captures) = RE_CHAIN.captures(path) {
+        let layer_name = parse_layer_name(&captures[1]).ok_or(SpecParseError::BadLayerName)?;
+
+        Ok(ResourceSpec::LayerChain(layer_name))
+    } else if let Some(captures) = RE_META.captures(path) {
+        let layer_name = parse_layer_name(&captures[1]).ok_or(SpecParseError::BadLayerName)?;
+
+        Ok(ResourceSpec::LayerMeta(layer_name))
+    } else if let Some(captures) = RE_DIFF.captures(path) {
+        let child = parse_layer_name(&captures[1]).ok_or(SpecParseError::BadLayerName)?;
+        let parent = parse_layer_name(&captures[2]).ok_or(SpecParseError::BadLayerName)?;
+
+        Ok(ResourceSpec::ManifestDiff(child, parent))
+    } else if let Some(captures) = RE_BENCH.captures(path) {
+        let size = captures[1]
+            .parse()
+            .map_err(|_e| SpecParseError::UnknownPath)?;
+
+        Ok(ResourceSpec::Bench(size))
+    } else if let Some(captures) = RE_LAYER_PATH.captures(path) {
+        // A path that would be valid if not for the layer name being
+        // the wrong length or containing anything but lowercase hex.
+        if is_layer_name(&captures[1]) {
+            Err(SpecParseError::UnknownPath)
+        } else {
+            Err(SpecParseError::BadLayerName)
+        }
     } else {
         Err(SpecParseError::UnknownPath)
     }
 }
 
+/// Groups of endpoints that get their own timeout.
+enum EndpointKind {
+    Metadata,
+    Download,
+    Upload,
+}
+
+/// The file ranges of a layer, as listed by `get_layer_manifest`.
+type Manifest = Arc<Vec<(LayerFileEnum, Range<usize>)>>;
+
+/// State that lives as long as a single client connection.
+struct ConnContext {
+    remote_addr: SocketAddr,
+    /// Layer headers recently parsed for /range requests, so that a
+    /// client pipelining many of them only pays for parsing once.
+    manifests: std::sync::Mutex<HashMap<LayerName, (Instant, Manifest)>>,
+}
+
+impl ConnContext {
+    fn new(remote_addr: SocketAddr) -> Arc<Self> {
+        Arc::new(ConnContext {
+            remote_addr,
+            manifests: std::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn manifest(&self, layer: LayerName, window: Duration) -> Option<Manifest> {
+        let manifests = self.manifests.lock().unwrap();
+        let (parsed_at, manifest) = manifests.get(&layer)?;
+
+        (parsed_at.elapsed() < window).then(|| manifest.clone())
+    }
+
+    fn remember_manifest(&self, layer: LayerName, manifest: Manifest, window: Duration) {
+        let mut manifests = self.manifests.lock().unwrap();
+        manifests.retain(|_, (parsed_at, _)| parsed_at.elapsed() < window);
+        manifests.insert(layer, (Instant::now(), manifest));
+    }
+}
+
 struct Service {
     manager: Arc<LayerManager>,
+    config: Config,
+    aliases: HashMap<LayerName, LayerName>,
+    acl: Option<Acl>,
+    cors: Option<Cors>,
+    _primary_watcher: Option<notify::RecommendedWatcher>,
+    /// Requests seen so far, for sampling the access log.
+    requests: AtomicU64,
 }
 
 impl Service {
-    fn new<P1: Into<PathBuf>, P2: Into<PathBuf>, P3: Into<PathBuf>, P4: Into<PathBuf>>(
-        primary_path: P1,
-        local_path: P2,
-        upload_path: P3,
-        scratch_path: P4,
-    ) -> Self {
-        Service {
-            manager: Arc::new(LayerManager::new(
-                primary_path,
-                local_path,
-                upload_path,
-                scratch_path,
-            )),
+    fn new(config: Config) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        name::set_paranoid(config.paranoid);
+        let aliases = match &config.alias_map {
+            Some(path) => load_alias_map(path)?,
+            None => HashMap::new(),
+        };
+        let acl = config.acl.as_ref().map(Acl::load).transpose()?;
+        let cors = Cors::new(config.cors_origin.clone());
+        let manager = Arc::new(LayerManager::new(&config));
+        {
+            let manager = manager.clone();
+            tokio::spawn(async move { manager.check_devices().await });
+        }
+        if config.preparse_headers {
+            tokio::spawn(manager.clone().preparse_headers());
         }
+        let primary_watcher = if config.watch_primary {
+            Some(manager.clone().watch_primary()?)
+        } else {
+            None
+        };
+        Ok(Service {
+            manager,
+            config,
+            aliases,
+            acl,
+            cors,
+            _primary_watcher: primary_watcher,
+            requests: AtomicU64::new(0),
+        })
     }
-    async fn serve(&self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
-        match req.method() {
-            &Method::GET => self.get(req).await,
-            &Method::POST => self.post(req).await,
-            _ => self.invalid(req, InvalidReason::BadMethod).await,
+
+    async fn serve(
+        &self,
+        req: Request<Body>,
+        conn: Arc<ConnContext>,
+    ) -> Result<Response<Body>, Infallible> {
+        let started = Instant::now();
+        let method = req.method().clone();
+        let path = req.uri().path().to_owned();
+        let remote_addr = conn.remote_addr;
+        let origin = req.headers().get("Origin").cloned();
+        let mut response = self.handle(req, conn).await;
+        if let (Some(cors), Ok(response)) = (&self.cors, &mut response) {
+            // preflights got theirs already
+            if method != Method::OPTIONS {
+                cors.add_headers(origin.as_ref(), response.headers_mut());
+            }
+        }
+        if let Ok(response) = &response {
+            self.log_access(
+                &method,
+                &path,
+                remote_addr,
+                response.status(),
+                started.elapsed(),
+            );
+        }
+
+        response
+    }
+
+    /// Log a request to the access log, unless it's sampled out. The
+    /// time taken is until the response head, not the end of the body.
+    fn log_access(
+        &self,
+        method: &Method,
+        path: &str,
+        remote_addr: SocketAddr,
+        status: hyper::StatusCode,
+        elapsed: Duration,
+    ) {
+        let count = self.requests.fetch_add(1, Ordering::Relaxed);
+        let sampled = count.is_multiple_of(self.config.access_log_sample.max(1));
+        let slow = self
+            .config
+            .access_log_slow_ms
+            .is_some_and(|ms| elapsed >= Duration::from_millis(ms));
+        if !(sampled || slow || status.is_server_error()) {
+            return;
         }
+
+        tracing::info!(
+            target: "access",
+            method = %method,
+            path,
+            remote_addr = %remote_addr,
+            status = status.as_u16(),
+            elapsed_ms = elapsed.as_millis() as u64,
+            slow,
+            "request"
+        );
     }
 
-    async fn get(&self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    async fn handle(
+        &self,
+        mut req: Request<Body>,
+        conn: Arc<ConnContext>,
+    ) -> Result<Response<Body>, Infallible> {
+        if let Some(acl) = &self.acl {
+            if !acl.allows(conn.remote_addr.ip(), req.method()) {
+                return Ok(error_response(403, "forbidden", "forbidden"));
+            }
+        }
+        // Browsers send preflights without credentials, so they can't
+        // be held to the auth token.
+        if let (Some(cors), &Method::OPTIONS) = (&self.cors, req.method()) {
+            return Ok(cors.preflight(&req));
+        }
+        // probes shouldn't depend on anything that could reject the path
+        if req.uri().path() == "/health"
+            && (req.method() == Method::GET || req.method() == Method::HEAD)
+        {
+            return Ok(self.health().await);
+        }
+        let is_read = req.method() == Method::GET || req.method() == Method::HEAD;
+        if (!is_read || self.config.auth_reads) && !self.is_authorized(&req) {
+            return Ok(unauthorized());
+        }
+        if let Some(uri) = self.resolve_alias(req.uri()) {
+            if self.config.alias_redirect {
+                // 301 lets clients turn anything into a GET, so other
+                // methods need a permanent redirect that keeps them.
+                let status = if req.method() == Method::GET || req.method() == Method::HEAD {
+                    301
+                } else {
+                    308
+                };
+                return Ok(Response::builder()
+                    .status(status)
+                    .header("Location", uri.to_string())
+                    .body(Body::empty())
+                    .unwrap());
+            }
+            *req.uri_mut() = uri;
+        }
+
         let spec = uri_to_spec(req.uri());
-        match spec {
-            Ok(ResourceSpec::Layer(layer)) => match self.manager.clone().get_layer(layer).await {
-                Ok(Some((size, stream))) => Ok(Response::builder()
-                    .header("Content-Length", size)
-                    .body(Body::wrap_stream(stream))
-                    .unwrap()),
-                Ok(None) => Ok(Response::builder()
-                    .status(404)
-                    .body("Layer not found".into())
-                    .unwrap()),
-                Err(e) => Ok(Response::builder()
-                    .status(500)
-                    .body(format!("Error: {e}").into())
-                    .unwrap()),
+        let layer = spec
+            .as_ref()
+            .ok()
+            .and_then(|spec| spec.layer())
+            .map(layer_name_to_string);
+        let span = tracing::info_span!(
+            "request",
+            method = %req.method(),
+            path = %req.uri().path(),
+            layer = layer.as_deref(),
+        );
+        if let Some(request_id) = req
+            .headers()
+            .get("X-Request-Id")
+            .and_then(|value| value.to_str().ok())
+        {
+            telemetry::set_request_id(&span, request_id);
+        }
+
+        let timeout = self.timeout_for(req.method(), &spec);
+        let response = async move {
+            match *req.method() {
+                Method::GET => self.get(req, &conn).await,
+                Method::HEAD => self.head(req, &conn).await,
+                Method::POST => self.post(req).await,
+                Method::PATCH => self.patch(req).await,
+                Method::DELETE => self.delete(req).await,
+                _ => self.invalid(req, InvalidReason::BadMethod).await,
+            }
+        }
+        .instrument(span);
+
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return response.await,
+        };
+        let deadline = tokio::time::Instant::now() + timeout;
+        match tokio::time::timeout_at(deadline, response).await {
+            Ok(Ok(response)) => {
+                // The deadline also covers streaming the body.
+                let (parts, body) = response.into_parts();
+                Ok(Response::from_parts(parts, with_deadline(body, deadline)))
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Ok(error_response(504, "timeout", "request timed out")),
+        }
+    }
+
+    /// 200 if all configured directories can be reached, 503 listing
+    /// the ones that can't otherwise.
+    async fn health(&self) -> Response<Body> {
+        let failed: serde_json::Map<_, _> = self
+            .manager
+            .path_devices()
+            .await
+            .into_iter()
+            .filter_map(|(name, _, device)| {
+                device
+                    .err()
+                    .map(|e| (name.to_string(), serde_json::Value::from(e.to_string())))
+            })
+            .collect();
+        if failed.is_empty() {
+            json_response(200, serde_json::json!({ "status": "ok" }))
+        } else {
+            json_response(
+                503,
+                serde_json::json!({ "status": "unavailable", "failed": failed }),
+            )
+        }
+    }
+
+    /// If the request is for an aliased layer, return the uri with the
+    /// alias replaced by the name the layer is stored under.
+    fn resolve_alias(&self, uri: &Uri) -> Option<Uri> {
+        if self.aliases.is_empty() {
+            return None;
+        }
+        let alias = uri_to_spec(uri).ok()?.layer()?;
+        let target = self.aliases.get(&alias)?;
+        let path = uri.path().replacen(
+            &layer_name_to_string(alias),
+            &layer_name_to_string(*target),
+            1,
+        );
+        let path_and_query = match uri.query() {
+            Some(query) => format!("{path}?{query}"),
+            None => path,
+        };
+
+        path_and_query.parse().ok()
+    }
+
+    fn timeout_for(
+        &self,
+        method: &Method,
+        spec: &Result<ResourceSpec, SpecParseError>,
+    ) -> Option<Duration> {
+        let kind = match (method, spec) {
+            // an event stream is meant to stay open until the layers show up
+            (_, Ok(ResourceSpec::LayerEvents(_))) => return None,
+            (&Method::GET, Ok(ResourceSpec::Layer(_)))
+            | (_, Ok(ResourceSpec::LayerFile(_, _)))
+            | (_, Ok(ResourceSpec::LayerFiles(_, _)))
+            | (_, Ok(ResourceSpec::LayerSegment(_, _, _)))
+            | (_, Ok(ResourceSpec::LayerChecksums(_)))
+            | (_, Ok(ResourceSpec::LayerIndexed(_)))
+            | (_, Ok(ResourceSpec::DictionaryBundle(_)))
+            | (_, Ok(ResourceSpec::Bundle))
+            | (_, Ok(ResourceSpec::Bench(_))) => EndpointKind::Download,
+            (&Method::POST, Ok(ResourceSpec::Layer(_)))
+            | (_, Ok(ResourceSpec::UploadFile(_)))
+            | (&Method::PATCH, Ok(ResourceSpec::UploadSession(_)))
+            | (_, Ok(ResourceSpec::UploadSessionFinish(_)))
+            | (_, Ok(ResourceSpec::StageFile(_, _)))
+            | (_, Ok(ResourceSpec::StageCommit(_))) => EndpointKind::Upload,
+            _ => EndpointKind::Metadata,
+        };
+        let seconds = match kind {
+            EndpointKind::Metadata => self.config.timeout_metadata,
+            EndpointKind::Download => self.config.timeout_download,
+            EndpointKind::Upload => self.config.timeout_upload,
+        };
+
+        seconds.map(Duration::from_secs)
+    }
+
+    async fn get(
+        &self,
+        req: Request<Body>,
+        conn: &ConnContext,
+    ) -> Result<Response<Body>, Infallible> {
+        let spec = uri_to_spec(req.uri());
+        // layers never change, so a client holding one can always keep it
+        if let Some(etag) = self.etag_for(&req, &spec) {
+            let matches = req
+                .headers()
+                .get("If-None-Match")
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| etag_matches(value, &etag));
+            if matches {
+                return Ok(Response::builder()
+                    .status(304)
+                    .header("ETag", etag)
+                    .header("Vary", "Accept-Encoding")
+                    .body(Body::empty())
+                    .unwrap());
+            }
+        }
+        let stream_slot = match spec {
+            Ok(ResourceSpec::Layer(_))
+            | Ok(ResourceSpec::LayerFile(_, _))
+            | Ok(ResourceSpec::LayerFiles(_, _))
+            | Ok(ResourceSpec::LayerIndexed(_))
+            | Ok(ResourceSpec::DictionaryBundle(_))
+            | Ok(ResourceSpec::LayerSegment(_, _, _))
+            | Ok(ResourceSpec::LayerChecksums(_))
+            | Ok(ResourceSpec::Bench(_)) => match self.manager.acquire_stream_slot() {
+                Some(slot) => Some(slot),
+                None => return Ok(self.too_many_streams()),
             },
+            _ => None,
+        };
+        match spec {
+            Ok(ResourceSpec::Layer(layer)) => {
+                let encoding = accepted_encoding(&req);
+                let validate = query_flag(req.uri(), "validate");
+                // Ranges are only served of the archive as it is on disk,
+                // and not while validating, which needs all of it.
+                let ranged = encoding.is_none() && !validate;
+                let requested = if ranged {
+                    req.headers()
+                        .get("Range")
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(parse_range_header)
+                } else {
+                    None
+                };
+                let with_parent = !self.config.no_parent_preload;
+                let result = if validate {
+                    self.manager
+                        .clone()
+                        .get_layer_validated(layer, with_parent)
+                        .await
+                        .map(|r| {
+                            r.map(|(size, parent, stream)| (0..size, size, parent, stream.boxed()))
+                        })
+                } else {
+                    self.manager
+                        .clone()
+                        .get_layer(layer, requested, with_parent)
+                        .await
+                        .map(|r| {
+                            r.map(|(part, size, parent, stream)| {
+                                (part, size, parent, stream.boxed())
+                            })
+                        })
+                };
+                match result {
+                    Ok(Some((part, size, parent, stream))) => {
+                        let mut builder = self
+                            .immutable_layer_headers(Response::builder(), layer, encoding)
+                            .header("Accept-Ranges", if ranged { "bytes" } else { "none" });
+                        if requested.is_some() {
+                            builder = builder.status(206).header(
+                                "Content-Range",
+                                format!("bytes {}-{}/{size}", part.start, part.end - 1),
+                            );
+                        }
+                        if let Some(parent) = parent {
+                            // clients almost always fetch the parent next
+                            builder = builder.header(
+                                "Link",
+                                format!("</layer/{}>; rel=preload", layer_name_to_string(parent)),
+                            );
+                        }
+                        // the compressed size isn't known up front, so
+                        // compressed layers are sent chunked
+                        let stream = match encoding {
+                            Some(encoding) => {
+                                builder = builder.header("Content-Encoding", encoding.as_str());
+                                compress_stream(stream, encoding)
+                            }
+                            None => {
+                                builder = builder.header("Content-Length", part.end - part.start);
+                                stream
+                            }
+                        };
+                        Ok(builder
+                            .header("Vary", "Accept-Encoding")
+                            .body(Body::wrap_stream(hold_while_streaming(stream, stream_slot)))
+                            .unwrap())
+                    }
+                    Ok(None) => Ok(self.not_found(layer, "layer not found")),
+                    Err(e) => match e
+                        .get_ref()
+                        .and_then(|inner| inner.downcast_ref::<RangeNotSatisfiable>())
+                    {
+                        Some(RangeNotSatisfiable { size }) => Ok(Response::builder()
+                            .status(416)
+                            .header("Content-Range", format!("bytes */{size}"))
+                            .body(Body::empty())
+                            .unwrap()),
+                        None => Ok(internal_error(e)),
+                    },
+                }
+            }
             Ok(ResourceSpec::LayerFile(layer, file)) => {
-                match self.manager.clone().get_layer_file(layer, file).await {
-                    Ok(Some((size, stream))) => Ok(Response::builder()
-                        .header("Content-Length", size)
-                        .body(Body::wrap_stream(stream))
-                        .unwrap()),
-                    Ok(None) => Ok(Response::builder().status(404).body(Body::empty()).unwrap()),
-                    Err(e) => Ok(Response::builder()
-                        .status(500)
-                        .body(format!("Error: {e}").into())
-                        .unwrap()),
+                let requested = req
+                    .headers()
+                    .get("Range")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(parse_range_header);
+                match self
+                    .manager
+                    .clone()
+                    .get_layer_file(layer, file, requested)
+                    .await
+                {
+                    Ok(FileLookup::Found((part, size, stream))) => {
+                        let mut builder = Response::builder()
+                            .header("Accept-Ranges", "bytes")
+                            .header("Content-Length", part.end - part.start);
+                        if let Some(etag) = self.etag_for(&req, &spec) {
+                            builder = builder.header("ETag", etag);
+                        }
+                        if requested.is_some() {
+                            builder = builder.status(206).header(
+                                "Content-Range",
+                                format!("bytes {}-{}/{size}", part.start, part.end - 1),
+                            );
+                        }
+                        Ok(builder
+                            .body(Body::wrap_stream(hold_while_streaming(stream, stream_slot)))
+                            .unwrap())
+                    }
+                    Ok(FileLookup::LayerMissing) => {
+                        Ok(self.file_not_found(layer, "layer_not_found"))
+                    }
+                    Ok(FileLookup::FileMissing) => {
+                        Ok(self.file_not_found(layer, "file_not_present"))
+                    }
+                    Err(e) if is_not_yet_available(&e) => Ok(too_early()),
+                    Err(e) if is_header_corrupt(&e) => Ok(header_corrupt(e)),
+                    Err(e) if is_header_truncated(&e) => Ok(header_truncated(e)),
+                    Err(e) => match e
+                        .get_ref()
+                        .and_then(|inner| inner.downcast_ref::<RangeNotSatisfiable>())
+                    {
+                        Some(RangeNotSatisfiable { size }) => Ok(Response::builder()
+                            .status(416)
+                            .header("Content-Range", format!("bytes */{size}"))
+                            .body(Body::empty())
+                            .unwrap()),
+                        None => Ok(internal_error(e)),
+                    },
                 }
             }
             Ok(ResourceSpec::LayerFileRange(layer, file)) => {
-                match self.manager.clone().get_layer_file_range(layer, file).await {
-                    Ok(Some(range)) => Ok(Response::builder()
+                match self.layer_file_range(conn, layer, file).await {
+                    // Unlike the plain text range, the end is exclusive,
+                    // as in /ranges.
+                    Ok(FileLookup::Found(range)) if wants_json(&req) => Ok(json_response(
+                        200,
+                        serde_json::json!({
+                            "file": file_enum_to_string(file).unwrap(),
+                            "start": range.start,
+                            "end": range.end,
+                            "length": range.end - range.start,
+                        }),
+                    )),
+                    // The range is inclusive, so an empty file comes out
+                    // as an end one below its start.
+                    Ok(FileLookup::Found(range)) => Ok(Response::builder()
                         .body(format!("{}-{}", range.start, range.end - 1).into())
                         .unwrap()),
-                    Ok(None) => Ok(Response::builder().status(404).body(Body::empty()).unwrap()),
-                    Err(e) => Ok(Response::builder()
-                        .status(500)
-                        .body(format!("Error: {e}").into())
+                    Ok(FileLookup::LayerMissing) => {
+                        Ok(self.file_not_found(layer, "layer_not_found"))
+                    }
+                    Ok(FileLookup::FileMissing) => {
+                        Ok(self.file_not_found(layer, "file_not_present"))
+                    }
+                    Err(e) if is_not_yet_available(&e) => Ok(too_early()),
+                    Err(e) if is_header_corrupt(&e) => Ok(header_corrupt(e)),
+                    Err(e) if is_header_truncated(&e) => Ok(header_truncated(e)),
+                    Err(e) => Ok(internal_error(e)),
+                }
+            }
+            Ok(ResourceSpec::LayerFiles(layer, files)) => {
+                match self.manager.clone().get_layer_files(layer, &files).await {
+                    Ok(Some((sizes, stream))) => {
+                        // Every file is framed as a "{name} {size}\n" line
+                        // followed by the file contents.
+                        let size: usize = sizes
+                            .iter()
+                            .map(|(file, size)| frame_header(*file, *size).len() + size)
+                            .sum();
+                        if let Some(response) = self.aggregate_too_large(size) {
+                            return Ok(response);
+                        }
+                        let stream = stream
+                            .map_ok(|(file, bytes)| {
+                                let header = Bytes::from(frame_header(file, bytes.len()));
+                                futures::stream::iter([Ok::<_, std::io::Error>(header), Ok(bytes)])
+                            })
+                            .try_flatten();
+                        Ok(Response::builder()
+                            .header("Content-Length", size)
+                            .body(Body::wrap_stream(hold_while_streaming(stream, stream_slot)))
+                            .unwrap())
+                    }
+                    Ok(None) => Ok(self.not_found(layer, "layer not found")),
+                    Err(e) if is_header_corrupt(&e) => Ok(header_corrupt(e)),
+                    Err(e) if is_header_truncated(&e) => Ok(header_truncated(e)),
+                    Err(e) => Ok(internal_error(e)),
+                }
+            }
+            Ok(ResourceSpec::LayerIndexed(layer)) => {
+                match self.manager.clone().get_layer_indexed(layer).await {
+                    Ok(Some((size, stream))) => Ok(Response::builder()
+                        .header("Content-Length", size)
+                        .body(Body::wrap_stream(hold_while_streaming(stream, stream_slot)))
                         .unwrap()),
+                    Ok(None) => Ok(self.not_found(layer, "layer not found")),
+                    Err(e) if is_header_corrupt(&e) => Ok(header_corrupt(e)),
+                    Err(e) if is_header_truncated(&e) => Ok(header_truncated(e)),
+                    Err(e) => Ok(internal_error(e)),
                 }
             }
-            Ok(_) => Ok(Response::builder()
-                .status(500)
-                .body("Unimplemented".into())
-                .unwrap()),
-            Err(e) => Ok(Response::builder()
-                .status(500)
-                .body(format!("Error: {e:?}").into())
+            Ok(ResourceSpec::DictionaryBundle(layer)) => {
+                match self.manager.clone().get_dictionary_bundle(layer).await {
+                    Ok(Some((size, stream))) => match self.aggregate_too_large(size) {
+                        Some(response) => Ok(response),
+                        None => Ok(Response::builder()
+                            .header("Content-Encoding", "gzip")
+                            .header("Content-Length", size)
+                            .body(Body::wrap_stream(hold_while_streaming(stream, stream_slot)))
+                            .unwrap()),
+                    },
+                    Ok(None) => Ok(self.not_found(layer, "layer not found")),
+                    Err(e) if is_header_corrupt(&e) => Ok(header_corrupt(e)),
+                    Err(e) if is_header_truncated(&e) => Ok(header_truncated(e)),
+                    Err(e) => Ok(internal_error(e)),
+                }
+            }
+            Ok(ResourceSpec::LayerSegment(layer, index, count)) => {
+                match self
+                    .manager
+                    .clone()
+                    .get_layer_segment(layer, index, count)
+                    .await
+                {
+                    Ok(Some((size, stream))) => Ok(Response::builder()
+                        .header("Content-Length", size)
+                        .body(Body::wrap_stream(hold_while_streaming(stream, stream_slot)))
+                        .unwrap()),
+                    Ok(None) => Ok(self.not_found(layer, "layer not found")),
+                    Err(e) => Ok(internal_error(e)),
+                }
+            }
+            Ok(ResourceSpec::LayerChecksums(layer)) => {
+                let permit = match self.manager.try_acquire_checksum_permit() {
+                    Some(permit) => permit,
+                    None => {
+                        return Ok(with_retry_after(error_response(
+                            503,
+                            "too_many_checksums",
+                            "too many concurrent checksum requests",
+                        )))
+                    }
+                };
+                match self.manager.clone().get_layer_checksums(layer).await {
+                    Ok(Some(stream)) => {
+                        let stream = stream.map_ok(|(file, range, digest)| {
+                            let mut line = serde_json::json!({
+                                "file": file_enum_to_string(file).unwrap(),
+                                "start": range.start,
+                                "end": range.end,
+                                "sha256": digest,
+                            })
+                            .to_string();
+                            line.push('\n');
+                            Bytes::from(line)
+                        });
+                        let stream = hold_while_streaming(stream, (stream_slot, permit));
+                        Ok(Response::builder()
+                            .header("Content-Type", "application/x-ndjson")
+                            .body(Body::wrap_stream(stream))
+                            .unwrap())
+                    }
+                    Ok(None) => Ok(self.not_found(layer, "layer not found")),
+                    Err(e) => Ok(internal_error(e)),
+                }
+            }
+            Ok(ResourceSpec::Cache(layer)) => match self.manager.cache_status(layer).await {
+                Ok(Some(status)) => Ok(json_response(
+                    200,
+                    serde_json::json!({
+                        "layer": layer_name_to_string(layer),
+                        "status": status.as_str(),
+                    }),
+                )),
+                Ok(None) => Ok(self.not_found(layer, "layer not found")),
+                Err(e) => Ok(internal_error(e)),
+            },
+            Ok(ResourceSpec::Bench(size)) => Ok(bench_response(size, stream_slot)),
+            Ok(ResourceSpec::LayerChain(layer)) => {
+                match self.manager.clone().get_layer_chain(layer).await {
+                    Ok(Some(chain)) => {
+                        let chain: Vec<_> = chain.into_iter().map(layer_name_to_string).collect();
+                        Ok(json_response(200, serde_json::json!(chain)))
+                    }
+                    Ok(None) => Ok(self.not_found(layer, "layer not found")),
+                    Err(e) if is_broken_chain(&e) => Ok(error_response(502, "broken_chain", e)),
+                    Err(e) => Ok(internal_error(e)),
+                }
+            }
+            Ok(ResourceSpec::LayerMeta(layer)) => {
+                match self.manager.clone().get_layer_meta(layer).await {
+                    Ok(Some(meta)) => {
+                        // ends are exclusive, as in /ranges
+                        let files: serde_json::Map<_, _> = meta
+                            .files
+                            .into_iter()
+                            .map(|(file, range)| {
+                                (
+                                    file_enum_to_string(file).unwrap().to_string(),
+                                    serde_json::json!({ "start": range.start, "end": range.end }),
+                                )
+                            })
+                            .collect();
+                        Ok(json_response(
+                            200,
+                            serde_json::json!({
+                                "size": meta.size,
+                                "source": if meta.local { "local" } else { "primary" },
+                                "files": files,
+                            }),
+                        ))
+                    }
+                    Ok(None) => Ok(self.not_found(layer, "layer not found")),
+                    Err(e) if is_header_corrupt(&e) => Ok(header_corrupt(e)),
+                    Err(e) if is_header_truncated(&e) => Ok(header_truncated(e)),
+                    Err(e) => Ok(internal_error(e)),
+                }
+            }
+            Ok(ResourceSpec::ManifestDiff(child, parent)) => {
+                let any = query_flag(req.uri(), "any");
+                match self.manifest_diff(child, parent, any).await {
+                    Ok(response) => Ok(response),
+                    Err(e) => Ok(internal_error(e)),
+                }
+            }
+            Ok(ResourceSpec::LayerEvents(layers)) => {
+                let stream = self
+                    .manager
+                    .clone()
+                    .watch_primary_layers(layers, LAYER_EVENTS_INTERVAL)
+                    .map_ok(|layer| {
+                        Bytes::from(format!(
+                            "event: available\ndata: {}\n\n",
+                            layer_name_to_string(layer)
+                        ))
+                    });
+                Ok(Response::builder()
+                    .header("Content-Type", "text/event-stream")
+                    .header("Cache-Control", "no-cache")
+                    .body(Body::wrap_stream(stream))
+                    .unwrap())
+            }
+            Ok(ResourceSpec::UploadSession(session)) => {
+                match self.manager.upload_session_offset(session).await {
+                    Ok(offset) => Ok(json_response(200, serde_json::json!({ "offset": offset }))),
+                    Err(e) => Ok(session_error(e)),
+                }
+            }
+            Ok(ResourceSpec::Stats) => {
+                let warmup = self.manager.warmups().histogram();
+                let buckets: Vec<_> = WARMUP_BUCKETS_MS
+                    .iter()
+                    .map(|bound| serde_json::Value::from(*bound))
+                    .chain([serde_json::Value::Null])
+                    .zip(warmup.buckets)
+                    .map(|(le, count)| serde_json::json!({ "le_ms": le, "count": count }))
+                    .collect();
+                let block_cache = self.manager.block_cache_stats().map(|stats| {
+                    serde_json::json!({
+                        "hits": stats.hits,
+                        "misses": stats.misses,
+                        "blocks": stats.blocks,
+                        "capacity": stats.capacity,
+                    })
+                });
+                Ok(json_response(
+                    200,
+                    serde_json::json!({
+                        "open_streams": self.manager.open_streams(),
+                        "max_open_streams": self.manager.max_open_streams(),
+                        "upload_path_unwritable": self.manager.upload_path_unwritable(),
+                        "cache_paused": self.manager.cache_paused(),
+                        "cache_warmup": {
+                            "count": warmup.count,
+                            "sum_ms": warmup.sum.as_millis() as u64,
+                            "buckets": buckets,
+                        },
+                        "block_cache": block_cache,
+                    }),
+                ))
+            }
+            Ok(ResourceSpec::Metrics) => Ok(Response::builder()
+                .status(200)
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .body(self.manager.render_metrics().await.into())
                 .unwrap()),
+            Ok(ResourceSpec::Version) => Ok(json_response(
+                200,
+                serde_json::json!({
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "commit": option_env!("GIT_COMMIT"),
+                    "terminus_store": option_env!("TERMINUS_STORE_VERSION"),
+                }),
+            )),
+            Ok(ResourceSpec::FileNames) => {
+                let names: Vec<_> = all_layer_files().filter_map(file_enum_to_string).collect();
+                Ok(json_response(200, serde_json::json!(names)))
+            }
+            Ok(ResourceSpec::Readyz) => {
+                let progress = self.manager.preparse_progress();
+                let ready = !self.config.preparse_headers
+                    || self.config.preparse_background
+                    || progress.finished();
+                Ok(json_response(
+                    if ready { 200 } else { 503 },
+                    serde_json::json!({
+                        "ready": ready,
+                        "preparse": {
+                            "done": progress.done(),
+                            "total": progress.total(),
+                            "finished": progress.finished(),
+                        },
+                    }),
+                ))
+            }
+            Ok(ResourceSpec::CachedLayers(limit)) => {
+                match self.manager.cached_layers(limit).await {
+                    Ok(layers) => {
+                        let layers: Vec<_> = layers.into_iter().map(layer_name_to_string).collect();
+                        Ok(json_response(200, serde_json::json!(layers)))
+                    }
+                    Err(e) => Ok(internal_error(e)),
+                }
+            }
+            Ok(ResourceSpec::RecentCache) => {
+                let warmups: Vec<_> = self
+                    .manager
+                    .warmups()
+                    .recent()
+                    .into_iter()
+                    .map(|warmup| {
+                        serde_json::json!({
+                            "layer": layer_name_to_string(warmup.layer),
+                            "copy_ms": warmup.copy.map(|copy| copy.as_millis() as u64),
+                            "warmup_ms": warmup.latency.as_millis() as u64,
+                            "served_at": warmup
+                                .served_at
+                                .duration_since(std::time::UNIX_EPOCH)
+                                .map(|since| since.as_secs())
+                                .unwrap_or(0),
+                        })
+                    })
+                    .collect();
+                Ok(json_response(200, serde_json::json!({ "layers": warmups })))
+            }
+            Ok(ResourceSpec::AdminConfig) => {
+                if let Some(response) = self.admin_refusal(&req) {
+                    return Ok(response);
+                }
+                Ok(json_response(200, self.config.to_json()))
+            }
+            Ok(ResourceSpec::AdminPrimaryUsage) => {
+                if let Some(response) = self.admin_refusal(&req) {
+                    return Ok(response);
+                }
+                match self.manager.primary_usage().await {
+                    Ok((usage, age)) => Ok(json_response(
+                        200,
+                        serde_json::json!({
+                            "bytes": usage.bytes,
+                            "layers": usage.layers,
+                            "age_secs": age.as_secs(),
+                        }),
+                    )),
+                    Err(e) => Ok(internal_error(e)),
+                }
+            }
+            Ok(ResourceSpec::AdminMounts) => {
+                if let Some(response) = self.admin_refusal(&req) {
+                    return Ok(response);
+                }
+                let devices = self.manager.path_devices().await;
+                let device_of = |name| {
+                    devices
+                        .iter()
+                        .find(|(n, _, _)| *n == name)
+                        .and_then(|(_, _, device)| device.as_ref().ok().copied())
+                };
+                // Caching relies on an atomic rename from scratch to local,
+                // which is only possible within a single device.
+                let same_device = match (device_of("local"), device_of("scratch")) {
+                    (Some(local), Some(scratch)) => Some(local == scratch),
+                    _ => None,
+                };
+                let mut body = serde_json::Map::new();
+                for (name, path, device) in devices.iter() {
+                    let entry = match device {
+                        Ok(device) => serde_json::json!({
+                            "path": path.to_string_lossy(),
+                            "device": device,
+                        }),
+                        Err(e) => serde_json::json!({
+                            "path": path.to_string_lossy(),
+                            "error": e.to_string(),
+                        }),
+                    };
+                    body.insert(name.to_string(), entry);
+                }
+                body.insert(
+                    "local_scratch_same_device".to_string(),
+                    serde_json::json!(same_device),
+                );
+                Ok(json_response(200, body.into()))
+            }
+            Ok(_) => self.invalid(req, InvalidReason::BadMethod).await,
+            Err(e) => Ok(e.response()),
         }
     }
+    /// Answer like GET would, without the body. This still opens the
+    /// layer, which is what tells us whether it exists and how big it is.
+    async fn head(
+        &self,
+        req: Request<Body>,
+        conn: &ConnContext,
+    ) -> Result<Response<Body>, Infallible> {
+        match uri_to_spec(req.uri()) {
+            Ok(ResourceSpec::Layer(_)) | Ok(ResourceSpec::LayerFile(_, _)) => {
+                let (parts, _body) = self.get(req, conn).await?.into_parts();
+                Ok(Response::from_parts(parts, Body::empty()))
+            }
+            _ => self.invalid(req, InvalidReason::BadMethod).await,
+        }
+    }
+
     async fn post(&self, mut req: Request<Body>) -> Result<Response<Body>, Infallible> {
         let spec = uri_to_spec(req.uri());
         match spec {
             Ok(ResourceSpec::Cache(layer)) => {
-                self.manager.clone().spawn_cache_layer(layer).await;
-                Ok(Response::builder().status(204).body(Body::empty()).unwrap())
+                if !self.manager.caching_allowed() {
+                    return Ok(error_response(
+                        507,
+                        "cache_paused",
+                        "local disk is above the cache high watermark",
+                    ));
+                }
+                // the usual 404 body, with the state added
+                let not_found = || {
+                    let mut body = serde_json::json!({
+                        "state": "not_found",
+                        "error": "not_found",
+                        "message": "layer not found",
+                    });
+                    if self.config.report_missing_layer {
+                        body["layer"] = layer_name_to_string(layer).into();
+                    }
+                    json_response(404, body)
+                };
+                if !query_flag(req.uri(), "wait") {
+                    return match self.manager.clone().queue_cache_layer(layer).await {
+                        Ok(QueueOutcome::AlreadyCached) => Ok(json_response(
+                            200,
+                            serde_json::json!({ "state": "already_cached" }),
+                        )),
+                        Ok(QueueOutcome::Queued) => {
+                            Ok(json_response(202, serde_json::json!({ "state": "queued" })))
+                        }
+                        Ok(QueueOutcome::NotInPrimary) => Ok(not_found()),
+                        Err(e) => Ok(internal_error(e)),
+                    };
+                }
+                match self.manager.clone().cache_layer_blocking(layer).await {
+                    Ok(CopyOutcome::Copied) => {
+                        Ok(json_response(200, serde_json::json!({ "state": "copied" })))
+                    }
+                    Ok(CopyOutcome::AlreadyCached) => Ok(json_response(
+                        200,
+                        serde_json::json!({ "state": "already_cached" }),
+                    )),
+                    Ok(CopyOutcome::NotInPrimary) => Ok(not_found()),
+                    Ok(CopyOutcome::NoRoom) => Ok(error_response(
+                        507,
+                        "cache_full",
+                        "layer doesn't fit in the local cache",
+                    )),
+                    Err(e) => Ok(internal_error(e)),
+                }
             }
             Ok(ResourceSpec::Layer(layer)) => {
+                let encoding = match req
+                    .headers()
+                    .get("Content-Encoding")
+                    .map(|value| value.to_str())
+                {
+                    None | Some(Ok("identity")) => UploadEncoding::Identity,
+                    Some(Ok("gzip")) => UploadEncoding::Gzip,
+                    _ => {
+                        let mut response = error_response(
+                            415,
+                            "unsupported_encoding",
+                            "unsupported content encoding",
+                        );
+                        response
+                            .headers_mut()
+                            .insert("Accept-Encoding", "gzip".parse().unwrap());
+                        return Ok(response);
+                    }
+                };
+                // A compressed body can be smaller or larger than what it
+                // decompresses to, so only the length of a plain body says
+                // how large the layer is.
+                let expected_size = match encoding {
+                    UploadEncoding::Identity => content_length(&req),
+                    UploadEncoding::Gzip => None,
+                };
+                if let (Some(limit), Some(size)) = (self.config.max_upload_size, expected_size) {
+                    if size > limit {
+                        return Ok(error_response(
+                            413,
+                            "upload_too_large",
+                            UploadTooLarge { limit },
+                        ));
+                    }
+                }
                 match self
                     .manager
                     .clone()
-                    .upload_layer(layer, req.body_mut())
+                    .upload_layer(layer, req.body_mut(), encoding, expected_size)
                     .await
                 {
-                    Ok(()) => Ok(Response::builder().status(204).body(Body::empty()).unwrap()),
-                    Err(e) => Ok(Response::builder()
-                        .status(500)
-                        .body(format!("Error: {e:?}").into())
-                        .unwrap()),
+                    Ok(UploadOutcome::Uploaded) => {
+                        Ok(Response::builder().status(204).body(Body::empty()).unwrap())
+                    }
+                    Ok(UploadOutcome::AlreadyExists) => Ok(json_response(
+                        200,
+                        serde_json::json!({ "state": "already_exists" }),
+                    )),
+                    Err(e) if e.is::<UploadTooLarge>() => {
+                        Ok(error_response(413, "upload_too_large", e))
+                    }
+                    Err(e)
+                        if e.downcast_ref::<std::io::Error>()
+                            .is_some_and(is_invalid_archive) =>
+                    {
+                        Ok(error_response(422, "invalid_archive", e))
+                    }
+                    Err(e) if e.is::<UploadStalled>() => {
+                        // the rest of the body is never going to be read
+                        let mut response = error_response(408, "upload_stalled", e);
+                        response
+                            .headers_mut()
+                            .insert("Connection", "close".parse().unwrap());
+                        Ok(response)
+                    }
+                    Err(e) if e.is::<TooManyUploads>() => {
+                        Ok(with_retry_after(error_response(503, "too_many_uploads", e)))
+                    }
+                    Err(e)
+                        if e.downcast_ref::<std::io::Error>()
+                            .is_some_and(is_upload_path_unwritable) =>
+                    {
+                        Ok(upload_path_unwritable())
+                    }
+                    Err(e)
+                        if e.downcast_ref::<std::io::Error>()
+                            .is_some_and(is_dest_is_dir) =>
+                    {
+                        Ok(dest_is_dir(e.downcast_ref().unwrap()))
+                    }
+                    Err(e) => Ok(internal_error(e)),
                 }
             }
             Ok(ResourceSpec::UploadFile(layer)) => {
                 let file_name = req.headers().get("upload-path");
                 if file_name.is_none() {
-                    return Ok(Response::builder()
-                        .status(400)
-                        .body(format!("upload path unspecified").into())
-                        .unwrap());
+                    return Ok(error_response(
+                        400,
+                        "bad_upload_path",
+                        "upload path unspecified",
+                    ));
                 }
                 let file_name = file_name.unwrap().to_str();
                 if file_name.is_err() {
-                    return Ok(Response::builder()
-                        .status(400)
-                        .body(format!("invald upload path").into())
-                        .unwrap());
+                    return Ok(error_response(
+                        400,
+                        "bad_upload_path",
+                        "invalid upload path",
+                    ));
                 }
                 let file_name = file_name.unwrap();
                 if file_name.is_empty() {
-                    return Ok(Response::builder()
-                        .status(400)
-                        .body(format!("upload path is empty").into())
-                        .unwrap());
+                    return Ok(error_response(
+                        400,
+                        "bad_upload_path",
+                        "upload path is empty",
+                    ));
                 }
 
                 match self
                     .manager
                     .clone()
-                    .move_uploaded_outside_layer(layer, &file_name)
+                    .move_uploaded_outside_layer(layer, file_name)
                     .await
                 {
                     Ok(()) => Ok(Response::builder().status(204).body(Body::empty()).unwrap()),
-                    Err(e) => Ok(Response::builder()
-                        .status(500)
-                        .body(format!("Error: {e:?}").into())
-                        .unwrap()),
+                    Err(e) if e.get_ref().is_some_and(|inner| inner.is::<BadUploadPath>()) => {
+                        Ok(error_response(400, "bad_upload_path", e))
+                    }
+                    Err(e) if is_invalid_archive(&e) => {
+                        Ok(error_response(422, "invalid_archive", e))
+                    }
+                    Err(e) if is_dest_is_dir(&e) => Ok(dest_is_dir(&e)),
+                    Err(e) => Ok(internal_error(e)),
                 }
             }
-            Ok(_) => Ok(Response::builder()
-                .status(500)
-                .body("Unimplemented".into())
-                .unwrap()),
-            Err(e) => Ok(Response::builder()
-                .status(500)
-                .body(format!("Error: {e:?}").into())
-                .unwrap()),
+            Ok(ResourceSpec::UploadStart) => match self.manager.start_upload_session().await {
+                Ok(session) => Ok(json_response(
+                    201,
+                    serde_json::json!({ "session": session.to_string() }),
+                )),
+                Err(e) if is_upload_path_unwritable(&e) => Ok(upload_path_unwritable()),
+                Err(e) => Ok(internal_error(e)),
+            },
+            Ok(ResourceSpec::UploadSessionFinish(session)) => {
+                let layer = match query_param(req.uri(), "layer").and_then(parse_layer_name) {
+                    Some(layer) => layer,
+                    _ => {
+                        return Ok(error_response(
+                            400,
+                            "bad_layer_name",
+                            "missing or invalid layer name",
+                        ))
+                    }
+                };
+                match self
+                    .manager
+                    .clone()
+                    .finish_upload_session(session, layer)
+                    .await
+                {
+                    Ok(()) => Ok(Response::builder().status(204).body(Body::empty()).unwrap()),
+                    Err(e) => Ok(session_error(e)),
+                }
+            }
+            Ok(ResourceSpec::StageFile(layer, file)) => {
+                match self
+                    .manager
+                    .stage_layer_file(layer, file, req.body_mut())
+                    .await
+                {
+                    Ok(()) => Ok(Response::builder().status(204).body(Body::empty()).unwrap()),
+                    Err(e) => Ok(stage_error(e)),
+                }
+            }
+            Ok(ResourceSpec::Prefetch) => {
+                let layers = match read_layer_names(req.body_mut()).await {
+                    Ok(layers) => layers,
+                    Err(response) => return Ok(response),
+                };
+                if !self.manager.caching_allowed() {
+                    return Ok(error_response(
+                        507,
+                        "cache_paused",
+                        "local disk is above the cache high watermark",
+                    ));
+                }
+                match self.manager.clone().prefetch_layers(layers).await {
+                    Ok(queued) => Ok(json_response(202, serde_json::json!({ "queued": queued }))),
+                    Err(e) => Ok(internal_error(e)),
+                }
+            }
+            Ok(ResourceSpec::Bundle) => {
+                let layers = match read_layer_names(req.body_mut()).await {
+                    Ok(layers) => layers,
+                    Err(response) => return Ok(response),
+                };
+                let stream_slot = match self.manager.acquire_stream_slot() {
+                    Some(slot) => slot,
+                    None => return Ok(self.too_many_streams()),
+                };
+                match self.manager.clone().bundle_layers(layers).await {
+                    Ok(Ok((size, stream))) => match self.aggregate_too_large(size) {
+                        Some(response) => Ok(response),
+                        None => Ok(Response::builder()
+                            .header("Content-Type", "application/x-tar")
+                            .header("Content-Length", size)
+                            .body(Body::wrap_stream(hold_while_streaming(stream, stream_slot)))
+                            .unwrap()),
+                    },
+                    Ok(Err(missing)) => {
+                        let missing: Vec<_> =
+                            missing.into_iter().map(layer_name_to_string).collect();
+                        Ok(json_response(
+                            404,
+                            serde_json::json!({
+                                "error": "not_found",
+                                "message": "layers not found",
+                                "missing": missing,
+                            }),
+                        ))
+                    }
+                    Err(e) => Ok(internal_error(e)),
+                }
+            }
+            Ok(ResourceSpec::FileRanges(layer)) => {
+                let body = match read_small_body(req.body_mut(), MAX_RANGES_BODY).await {
+                    Ok(Some(body)) => body,
+                    Ok(None) => {
+                        return Ok(error_response(
+                            413,
+                            "body_too_large",
+                            "request body too large",
+                        ))
+                    }
+                    Err(e) => return Ok(error_response(400, "bad_body", e)),
+                };
+                let names: Vec<String> = match serde_json::from_slice(&body) {
+                    Ok(names) => names,
+                    Err(e) => return Ok(error_response(400, "bad_body", e)),
+                };
+                let mut files = Vec::with_capacity(names.len());
+                for name in &names {
+                    match file_name_to_enum(name) {
+                        Some(file) => files.push(file),
+                        None => {
+                            return Ok(error_response(
+                                400,
+                                "unknown_file",
+                                format!("unknown layer file {name}"),
+                            ))
+                        }
+                    }
+                }
+                let manifest = match self.manager.clone().get_layer_manifest(layer).await {
+                    Ok(Some(manifest)) => manifest,
+                    Ok(None) => return Ok(self.not_found(layer, "layer not found")),
+                    Err(e) if is_header_corrupt(&e) => return Ok(header_corrupt(e)),
+                    Err(e) if is_header_truncated(&e) => return Ok(header_truncated(e)),
+                    Err(e) => return Ok(internal_error(e)),
+                };
+                // Unlike /range, ends are exclusive, as in /layers/{name}/checksums.
+                let ranges: serde_json::Map<_, _> = names
+                    .into_iter()
+                    .zip(files)
+                    .map(|(name, file)| {
+                        let range = manifest
+                            .iter()
+                            .find(|(f, _)| *f == file)
+                            .map(|(_, range)| {
+                                serde_json::json!({ "start": range.start, "end": range.end })
+                            });
+                        (name, range.unwrap_or(serde_json::Value::Null))
+                    })
+                    .collect();
+                Ok(json_response(200, ranges.into()))
+            }
+            Ok(ResourceSpec::StageCommit(layer)) => {
+                match self.manager.clone().commit_staged_layer(layer).await {
+                    Ok(()) => Ok(Response::builder().status(204).body(Body::empty()).unwrap()),
+                    Err(e) => Ok(stage_error(e)),
+                }
+            }
+            Ok(_) => self.invalid(req, InvalidReason::BadMethod).await,
+            Err(e) => Ok(e.response()),
+        }
+    }
+
+    async fn patch(&self, mut req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let spec = uri_to_spec(req.uri());
+        match spec {
+            Ok(ResourceSpec::UploadSession(session)) => {
+                let range = req
+                    .headers()
+                    .get("Content-Range")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(parse_content_range);
+                let (start, end, total) = match range {
+                    Some(range) => range,
+                    None => {
+                        return Ok(error_response(
+                            400,
+                            "bad_content_range",
+                            "missing or invalid Content-Range",
+                        ))
+                    }
+                };
+                match self
+                    .manager
+                    .append_upload_session(session, start, end, total, req.body_mut())
+                    .await
+                {
+                    Ok(offset) => Ok(json_response(200, serde_json::json!({ "offset": offset }))),
+                    Err(e) => Ok(session_error(e)),
+                }
+            }
+            Ok(_) => self.invalid(req, InvalidReason::BadMethod).await,
+            Err(e) => Ok(e.response()),
+        }
+    }
+
+    async fn delete(&self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let spec = uri_to_spec(req.uri());
+        match spec {
+            Ok(ResourceSpec::Cache(layer)) => match self.manager.evict_layer(layer).await {
+                Ok(CacheStatus::Cached) => {
+                    Ok(Response::builder().status(204).body(Body::empty()).unwrap())
+                }
+                Ok(CacheStatus::Caching) => Ok(error_response(
+                    409,
+                    "caching",
+                    "layer is currently being cached",
+                )),
+                Ok(CacheStatus::Uncached) => Ok(self.not_found(layer, "layer not cached")),
+                Err(e) => Ok(internal_error(e)),
+            },
+            Ok(_) => self.invalid(req, InvalidReason::BadMethod).await,
+            Err(e) => Ok(e.response()),
+        }
+    }
+
+    /// Compare the file sizes of a layer with those of its parent.
+    /// Unless `any` is set, `parent` has to be the actual parent of
+    /// `child`.
+    async fn manifest_diff(
+        &self,
+        child: LayerName,
+        parent: LayerName,
+        any: bool,
+    ) -> std::io::Result<Response<Body>> {
+        let child_manifest = match self.manager.clone().get_layer_manifest(child).await? {
+            Some(manifest) => manifest,
+            None => return Ok(self.not_found(child, "layer not found")),
+        };
+        if !any && self.manager.clone().get_layer_parent(child).await? != Some(parent) {
+            return Ok(error_response(
+                400,
+                "not_parent",
+                "not the parent of this layer",
+            ));
+        }
+        let parent_manifest = match self.manager.clone().get_layer_manifest(parent).await? {
+            Some(manifest) => manifest,
+            None => return Ok(self.not_found(parent, "layer not found")),
+        };
+
+        let size_in = |manifest: &[(LayerFileEnum, std::ops::Range<usize>)], file| {
+            manifest
+                .iter()
+                .find(|(f, _)| *f == file)
+                .map(|(_, range)| range.len())
+        };
+        let files: Vec<_> = all_layer_files()
+            .filter_map(|file| {
+                let child_size = size_in(&child_manifest, file);
+                let parent_size = size_in(&parent_manifest, file);
+                if child_size.is_none() && parent_size.is_none() {
+                    return None;
+                }
+                let delta = child_size.unwrap_or(0) as i64 - parent_size.unwrap_or(0) as i64;
+                Some(serde_json::json!({
+                    "file": file_enum_to_string(file).unwrap(),
+                    "child": child_size,
+                    "parent": parent_size,
+                    "delta": delta,
+                }))
+            })
+            .collect();
+
+        Ok(json_response(
+            200,
+            serde_json::json!({
+                "child": layer_name_to_string(child),
+                "parent": layer_name_to_string(parent),
+                "files": files,
+            }),
+        ))
+    }
+
+    /// Build a 404 response for a missing layer. If configured, the
+    /// body names the requested layer so that clients can tell which
+    /// layer was missing after proxy rewrites.
+    fn not_found(&self, layer: LayerName, message: &str) -> Response<Body> {
+        let mut body = serde_json::json!({ "error": "not_found", "message": message });
+        if self.config.report_missing_layer {
+            body["layer"] = layer_name_to_string(layer).into();
+        }
+
+        json_response(404, body)
+    }
+
+    fn file_not_found(&self, layer: LayerName, error: &str) -> Response<Body> {
+        let message = match error {
+            "layer_not_found" => "layer not found",
+            _ => "file not present in layer",
+        };
+        let mut body = serde_json::json!({ "error": error, "message": message });
+        if self.config.report_missing_layer {
+            body["layer"] = layer_name_to_string(layer).into();
         }
+
+        json_response(404, body)
+    }
+
+    /// The absolute range of a file within a layer. With
+    /// --range-header-reuse-ms, headers parsed for earlier requests on
+    /// the same connection are reused.
+    async fn layer_file_range(
+        &self,
+        conn: &ConnContext,
+        layer: LayerName,
+        file: LayerFileEnum,
+    ) -> std::io::Result<FileLookup<Range<usize>>> {
+        let window = match self.config.range_header_reuse_ms {
+            Some(ms) => Duration::from_millis(ms),
+            None => return self.manager.clone().get_layer_file_range(layer, file).await,
+        };
+        let lookup = |manifest: &Manifest| {
+            manifest
+                .iter()
+                .find(|(f, _)| *f == file)
+                .map_or(FileLookup::FileMissing, |(_, range)| {
+                    FileLookup::Found(range.clone())
+                })
+        };
+        if let Some(manifest) = conn.manifest(layer, window) {
+            return Ok(lookup(&manifest));
+        }
+
+        match self.manager.clone().get_layer_manifest(layer).await? {
+            Some(manifest) => {
+                let manifest = Arc::new(manifest);
+                let range = lookup(&manifest);
+                conn.remember_manifest(layer, manifest, window);
+                Ok(range)
+            }
+            // layers that are still being uploaded aren't worth keeping
+            None => self.manager.clone().get_layer_file_range(layer, file).await,
+        }
+    }
+
+    /// Refuse a response that combines several files if it would be
+    /// larger than --max-aggregate-response-size.
+    fn aggregate_too_large(&self, size: usize) -> Option<Response<Body>> {
+        let limit = self.config.max_aggregate_response_size?;
+        if size as u64 <= limit {
+            return None;
+        }
+
+        Some(json_response(
+            413,
+            serde_json::json!({
+                "error": "response_too_large",
+                "size": size,
+                "limit": limit,
+            }),
+        ))
+    }
+
+    /// Mark a layer download as immutable, so that CDNs can cache it
+    /// indefinitely. Layers never change once written, which makes the
+    /// layer name a strong validator.
+    fn immutable_layer_headers(
+        &self,
+        builder: hyper::http::response::Builder,
+        layer: LayerName,
+        encoding: Option<DownloadEncoding>,
+    ) -> hyper::http::response::Builder {
+        if self.config.no_cdn_headers {
+            return builder;
+        }
+
+        builder
+            .header("Cache-Control", "public, max-age=31536000, immutable")
+            .header("ETag", layer_etag(layer, encoding))
+    }
+
+    /// The ETag of a layer download or layer file, if the resource has
+    /// one. Layer names never get reused, so they make for strong tags.
+    fn etag_for(
+        &self,
+        req: &Request<Body>,
+        spec: &Result<ResourceSpec, SpecParseError>,
+    ) -> Option<String> {
+        if self.config.no_cdn_headers {
+            return None;
+        }
+        match spec {
+            Ok(ResourceSpec::Layer(layer)) => Some(layer_etag(*layer, accepted_encoding(req))),
+            // a layer can be rolled up at any time, so its rollup file
+            // is the one file that can change
+            Ok(ResourceSpec::LayerFile(_, LayerFileEnum::Rollup)) => None,
+            Ok(ResourceSpec::LayerFile(layer, file)) => Some(format!(
+                "\"{}-{}\"",
+                layer_name_to_string(*layer),
+                file_enum_to_string(*file).unwrap()
+            )),
+            _ => None,
+        }
+    }
+
+    /// The response refusing a request for an administrative endpoint,
+    /// if it is to be refused. Those endpoints are only served when an
+    /// --auth-token is configured and presented, so they are never
+    /// open to anonymous callers.
+    fn admin_refusal(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        if self.config.auth_token.is_none() {
+            return Some(error_response(
+                403,
+                "admin_disabled",
+                "administrative endpoints require --auth-token to be configured",
+            ));
+        }
+
+        (!self.is_authorized(req)).then(unauthorized)
+    }
+
+    /// Check the bearer token if one is configured. Without a
+    /// configured token, every request is authorized.
+    fn is_authorized(&self, req: &Request<Body>) -> bool {
+        let expected = match &self.config.auth_token {
+            Some(token) => token,
+            None => return true,
+        };
+        let provided = req
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        match provided {
+            Some(provided) => constant_time_eq(provided.as_bytes(), expected.as_bytes()),
+            None => false,
+        }
+    }
+
+    fn too_many_streams(&self) -> Response<Body> {
+        with_retry_after(error_response(
+            503,
+            "too_many_streams",
+            "too many open streams",
+        ))
     }
 
     async fn invalid(
@@ -250,14 +1890,465 @@ impl Service {
         _req: Request<Body>,
         reason: InvalidReason,
     ) -> Result<Response<Body>, Infallible> {
-        Ok(Response::builder()
-            .status(reason.status())
-            .body(reason.msg())
-            .unwrap())
+        Ok(error_response(reason.status(), reason.code(), reason.msg()))
+    }
+}
+
+/// The line introducing a file in the body of a multi-file response.
+pub fn frame_header(file: LayerFileEnum, size: usize) -> String {
+    format!("{} {size}\n", file_enum_to_string(file).unwrap())
+}
+
+/// Stream `size` bytes of synthetic data, to measure how fast we can
+/// stream without any disk access getting in the way. `guard` is
+/// kept for as long as the body is streamed.
+#[cfg(feature = "bench")]
+fn bench_response<G: Send + 'static>(size: u64, guard: G) -> Response<Body> {
+    lazy_static! {
+        // a multiple of 256, so that the pattern continues across chunks
+        static ref BENCH_CHUNK: Bytes = (0..64 * 1024).map(|i| i as u8).collect();
+    }
+    let chunk_size = BENCH_CHUNK.len() as u64;
+    let chunks = (0..size.div_ceil(chunk_size)).map(move |i| {
+        let len = chunk_size.min(size - i * chunk_size) as usize;
+        Ok::<_, std::io::Error>(BENCH_CHUNK.slice(..len))
+    });
+
+    Response::builder()
+        .header("Content-Length", size)
+        .body(Body::wrap_stream(hold_while_streaming(
+            futures::stream::iter(chunks),
+            guard,
+        )))
+        .unwrap()
+}
+
+#[cfg(not(feature = "bench"))]
+fn bench_response<G>(_size: u64, _guard: G) -> Response<Body> {
+    Response::builder().status(404).body(Body::empty()).unwrap()
+}
+
+/// Look up a parameter in the query string of the uri.
+fn query_param<'a>(uri: &'a Uri, name: &str) -> Option<&'a str> {
+    uri.query()?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (key == name).then_some(value)
+    })
+}
+
+/// Whether a yes-or-no query parameter is set, as either `1` or `true`.
+fn query_flag(uri: &Uri, name: &str) -> bool {
+    matches!(query_param(uri, name), Some("1") | Some("true"))
+}
+
+/// Abort a response body that is still streaming once the deadline
+/// has passed. At that point the status has long been sent, so all
+/// that can be done is cutting the body short.
+fn with_deadline(body: Body, deadline: tokio::time::Instant) -> Body {
+    let stream = futures::stream::unfold((body, false), move |(mut body, expired)| async move {
+        if expired {
+            return None;
+        }
+        match tokio::time::timeout_at(deadline, body.next()).await {
+            Ok(Some(item)) => Some((
+                item.map_err(|e| -> Box<dyn std::error::Error + Send + Sync> { e.into() }),
+                (body, false),
+            )),
+            Ok(None) => None,
+            Err(_) => Some((Err("request timed out".into()), (body, true))),
+        }
+    });
+
+    Body::wrap_stream(stream)
+}
+
+/// The Content-Length of a request, if it has a valid one.
+fn content_length(req: &Request<Body>) -> Option<u64> {
+    req.headers()
+        .get("Content-Length")?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+fn json_response(status: u16, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(body.to_string().into())
+        .unwrap()
+}
+
+/// An error response in the shape every error is reported in: a
+/// stable code for clients to switch on, and a message for humans.
+fn error_response(status: u16, error: &str, message: impl std::fmt::Display) -> Response<Body> {
+    json_response(
+        status,
+        serde_json::json!({
+            "error": error,
+            "message": message.to_string(),
+        }),
+    )
+}
+
+/// Anything that went wrong on our end while doing I/O.
+fn internal_error(e: impl std::fmt::Display) -> Response<Body> {
+    error_response(500, "io_error", e)
+}
+
+fn with_retry_after(mut response: Response<Body>) -> Response<Body> {
+    response
+        .headers_mut()
+        .insert("Retry-After", hyper::header::HeaderValue::from(1));
+
+    response
+}
+
+fn session_error(e: SessionError) -> Response<Body> {
+    let (status, code) = match e {
+        SessionError::NotFound => (404, "session_not_found"),
+        SessionError::OffsetMismatch { offset } => {
+            // tell the client where to resume from
+            return json_response(
+                409,
+                serde_json::json!({
+                    "error": "offset_mismatch",
+                    "message": e.to_string(),
+                    "offset": offset,
+                }),
+            );
+        }
+        SessionError::Incomplete { .. } => (409, "session_incomplete"),
+        SessionError::LengthMismatch { .. } => (400, "length_mismatch"),
+        SessionError::BeyondTotal { .. } => (400, "bad_content_range"),
+        SessionError::TooLarge { .. } => (413, "upload_too_large"),
+        SessionError::TooManyUploads => {
+            return with_retry_after(error_response(503, "too_many_uploads", e))
+        }
+        SessionError::Io(ref e) if is_invalid_archive(e) => (422, "invalid_archive"),
+        SessionError::Io(ref e) if is_dest_is_dir(e) => return dest_is_dir(e),
+        SessionError::Io(_) => (500, "io_error"),
+    };
+
+    error_response(status, code, e)
+}
+
+fn stage_error(e: StageError) -> Response<Body> {
+    let (status, code) = match &e {
+        StageError::NotFound => (404, "nothing_staged"),
+        StageError::TooLarge { .. } => (413, "upload_too_large"),
+        StageError::MissingFiles(files) => {
+            let missing: Vec<_> = files
+                .iter()
+                .map(|file| file_enum_to_string(*file).unwrap())
+                .collect();
+            return json_response(
+                409,
+                serde_json::json!({
+                    "error": "missing_files",
+                    "message": e.to_string(),
+                    "missing": missing,
+                }),
+            );
+        }
+        StageError::Io(e) if is_invalid_archive(e) => (422, "invalid_archive"),
+        StageError::Io(e) if is_dest_is_dir(e) => return dest_is_dir(e),
+        StageError::Io(_) => (500, "io_error"),
+    };
+
+    error_response(status, code, e)
+}
+
+/// Read a request body holding a JSON array of layer names, as sent to
+/// POST /prefetch and /bundle, or the error response to send instead.
+async fn read_layer_names(body: &mut Body) -> Result<Vec<LayerName>, Response<Body>> {
+    let body = match read_small_body(body, MAX_LAYER_NAMES_BODY).await {
+        Ok(Some(body)) => body,
+        Ok(None) => {
+            return Err(error_response(
+                413,
+                "body_too_large",
+                "request body too large",
+            ))
+        }
+        Err(e) => return Err(error_response(400, "bad_body", e)),
+    };
+    let names: Vec<String> = match serde_json::from_slice(&body) {
+        Ok(names) => names,
+        Err(e) => return Err(error_response(400, "bad_body", e)),
+    };
+    let mut layers = Vec::with_capacity(names.len());
+    for name in &names {
+        match parse_layer_name(name) {
+            Some(layer) => layers.push(layer),
+            None => {
+                return Err(error_response(
+                    400,
+                    "bad_layer_name",
+                    format!("invalid layer name {name}"),
+                ))
+            }
+        }
+    }
+
+    Ok(layers)
+}
+
+/// Read a request body into memory, or None if it is larger than
+/// `limit`.
+async fn read_small_body(body: &mut Body, limit: usize) -> Result<Option<Bytes>, hyper::Error> {
+    let mut buf = bytes::BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        if buf.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+
+    Ok(Some(buf.freeze()))
+}
+
+/// Every encoding of a layer is a different representation, so it
+/// gets its own tag.
+fn layer_etag(layer: LayerName, encoding: Option<DownloadEncoding>) -> String {
+    match encoding {
+        Some(encoding) => format!("\"{}-{}\"", layer_name_to_string(layer), encoding.as_str()),
+        None => format!("\"{}\"", layer_name_to_string(layer)),
+    }
+}
+
+/// Whether an `If-None-Match` header matches a tag. The comparison is
+/// weak, as it should be for `If-None-Match`. `*` is never taken as a
+/// match, as that would need the layer to be looked up first.
+fn etag_matches(value: &str, etag: &str) -> bool {
+    value
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate.strip_prefix("W/").unwrap_or(candidate) == etag)
+}
+
+/// Whether the client asked for JSON, through `?format=json` or by
+/// accepting `application/json`.
+fn wants_json(req: &Request<Body>) -> bool {
+    query_param(req.uri(), "format") == Some("json")
+        || req
+            .headers()
+            .get("Accept")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.contains("application/json"))
+}
+
+fn accepted_encoding(req: &Request<Body>) -> Option<DownloadEncoding> {
+    req.headers()
+        .get("Accept-Encoding")
+        .and_then(|value| value.to_str().ok())
+        .and_then(negotiate_encoding)
+}
+
+/// A compression layers can be downloaded with.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DownloadEncoding {
+    Zstd,
+    Gzip,
+}
+
+impl DownloadEncoding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+        }
+    }
+}
+
+/// Pick a compression from an `Accept-Encoding` header, preferring
+/// zstd. Layers are only compressed for clients that ask for it, as
+/// much of an archive may not compress well. Quality values other than
+/// zero are not ranked.
+fn negotiate_encoding(value: &str) -> Option<DownloadEncoding> {
+    let accepted: Vec<&str> = value
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            let coding = parts.next()?;
+            let refused = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (!refused).then_some(coding)
+        })
+        .collect();
+
+    [DownloadEncoding::Zstd, DownloadEncoding::Gzip]
+        .into_iter()
+        .find(|encoding| {
+            accepted
+                .iter()
+                .any(|coding| coding.eq_ignore_ascii_case(encoding.as_str()))
+        })
+}
+
+fn compress_stream(
+    stream: BoxStream<'static, std::io::Result<Bytes>>,
+    encoding: DownloadEncoding,
+) -> BoxStream<'static, std::io::Result<Bytes>> {
+    let reader = StreamReader::new(stream);
+    let encoder = match encoding {
+        DownloadEncoding::Zstd => Either::Left(ZstdEncoder::new(reader)),
+        DownloadEncoding::Gzip => Either::Right(GzipEncoder::new(reader)),
+    };
+
+    ReaderStream::new(encoder).boxed()
+}
+
+/// Parse a `Range: bytes=...` header asking for a single range.
+/// Anything else, including multiple ranges, is ignored so that the
+/// whole file is served instead.
+fn parse_range_header(value: &str) -> Option<ByteRange> {
+    let (start, end) = value.strip_prefix("bytes=")?.trim().split_once('-')?;
+    if start.is_empty() {
+        return Some(ByteRange::Suffix(end.parse().ok()?));
+    }
+    let start = start.parse().ok()?;
+    let end = match end {
+        "" => None,
+        end => Some(end.parse().ok()?),
+    };
+    if end.is_some_and(|end| end < start) {
+        return None;
     }
+
+    Some(ByteRange::From { start, end })
+}
+
+/// Parse a `Content-Range: bytes {start}-{end}/{total}` header into
+/// an exclusive range and the total size, which may be `*` if it is
+/// not known yet.
+fn parse_content_range(value: &str) -> Option<(u64, u64, Option<u64>)> {
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, last) = range.split_once('-')?;
+    let start: u64 = start.parse().ok()?;
+    let end = last.parse::<u64>().ok()?.checked_add(1)?;
+    let total = match total {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    if start >= end || total.is_some_and(|total| end > total) {
+        return None;
+    }
+
+    Some((start, end, total))
+}
+
+fn is_not_yet_available(e: &std::io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.is::<NotYetAvailable>())
+}
+
+fn is_header_corrupt(e: &std::io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<HeaderCorrupt>())
+}
+
+/// The layer exists, but its archive is truncated relative to its header.
+fn header_corrupt(e: std::io::Error) -> Response<Body> {
+    error_response(500, "header_corrupt", e)
+}
+
+fn is_header_truncated(e: &std::io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.is::<HeaderTruncated>())
+}
+
+/// The layer is too short to even hold its header, which points at a
+/// broken copy rather than a problem reading it.
+fn header_truncated(e: std::io::Error) -> Response<Body> {
+    error_response(502, "header_truncated", e)
+}
+
+fn is_invalid_archive(e: &std::io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.is::<InvalidArchive>())
+}
+
+fn is_dest_is_dir(e: &std::io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.is::<DestinationIsDirectory>())
+}
+
+/// Something is in the way of the upload in primary, which is a problem
+/// with the server rather than with the request.
+fn dest_is_dir(e: &std::io::Error) -> Response<Body> {
+    error_response(500, "dest_is_dir", e)
 }
 
+fn is_broken_chain(e: &std::io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<BrokenChain>())
+}
+
+fn is_upload_path_unwritable(e: &std::io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.is::<UploadPathUnwritable>())
+}
+
+fn upload_path_unwritable() -> Response<Body> {
+    error_response(503, "upload_path_unwritable", "upload path is not writable")
+}
+
+/// The requested part of a layer that is still being written isn't
+/// there yet.
+fn too_early() -> Response<Body> {
+    with_retry_after(error_response(
+        425,
+        "not_yet_available",
+        "layer is not available yet",
+    ))
+}
+
+fn unauthorized() -> Response<Body> {
+    let mut response = error_response(401, "unauthorized", "unauthorized");
+    response
+        .headers_mut()
+        .insert("WWW-Authenticate", "Bearer".parse().unwrap());
+
+    response
+}
+
+/// Compare two byte strings in time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Keep a stream slot (or any other guard) reserved for as long as the
+/// response body is alive.
+fn hold_while_streaming<S: futures::Stream, G: Send>(
+    stream: S,
+    guard: G,
+) -> impl futures::Stream<Item = S::Item> {
+    stream.map(move |item| {
+        let _guard = &guard;
+        item
+    })
+}
+
+/// The layer file with the given name, or with the given
+/// `LayerFileEnum` discriminant for clients that only have that. Only
+/// the plain decimal form of a discriminant is accepted, so every file
+/// has exactly one numeric name.
 pub fn file_name_to_enum(name: &str) -> Option<LayerFileEnum> {
+    if let Ok(id) = name.parse::<usize>() {
+        if id.to_string() != name {
+            return None;
+        }
+        return LayerFileEnum::from_usize(id);
+    }
     let result = match name {
         "node_dictionary_blocks" => LayerFileEnum::NodeDictionaryBlocks,
         "node_dictionary_offsets" => LayerFileEnum::NodeDictionaryOffsets,
@@ -344,13 +2435,13 @@ pub fn file_name_to_enum(name: &str) -> Option<LayerFileEnum> {
         }
 
         "parent" => LayerFileEnum::Parent,
+        "rollup" => LayerFileEnum::Rollup,
         _ => return None,
     };
 
     Some(result)
 }
 
-#[allow(unused)]
 pub fn file_enum_to_string(file: LayerFileEnum) -> Option<&'static str> {
     let result = match file {
         LayerFileEnum::NodeDictionaryBlocks => "node_dictionary_blocks",
@@ -438,39 +2529,204 @@ pub fn file_enum_to_string(file: LayerFileEnum) -> Option<&'static str> {
         }
 
         LayerFileEnum::Parent => "parent",
-        _ => return None,
+        LayerFileEnum::Rollup => "rollup",
     };
 
     Some(result)
 }
 
-pub async fn serve<P1: Into<PathBuf>, P2: Into<PathBuf>, P3: Into<PathBuf>, P4: Into<PathBuf>>(
-    primary_path: P1,
-    local_path: P2,
-    upload_path: P3,
-    scratch_path: P4,
-    port: u16,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
-    let service = Arc::new(Service::new(
-        primary_path,
-        local_path,
-        upload_path,
-        scratch_path,
-    ));
-
-    let make_svc = make_service_fn(move |_conn| {
+pub async fn serve(config: Config) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), config.port);
+    let tls_config = tls::server_config(&config)?;
+    let header_timeout = config.timeout_idle.map(Duration::from_secs);
+    check_dirs(&config).await?;
+    let service = Arc::new(Service::new(config)?);
+
+    if let Some(tls_config) = tls_config {
+        return serve_tls(addr, tls_config, service, header_timeout).await;
+    }
+
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
         let s = service.clone();
-        async {
+        let conn = ConnContext::new(conn.remote_addr());
+        async move {
             Ok::<_, Infallible>(service_fn(move |req| {
                 let s = s.clone();
-                async move { s.serve(req).await }
+                let conn = conn.clone();
+                async move { s.serve(req, conn).await }
             }))
         }
     });
 
-    let server = Server::bind(&addr).serve(make_svc);
+    let mut builder = Server::bind(&addr);
+    if let Some(timeout) = header_timeout {
+        builder = builder.http1_header_read_timeout(timeout);
+    }
+    let server = builder.serve(make_svc);
     server.await?;
 
     Ok(())
 }
+
+async fn serve_tls(
+    addr: SocketAddr,
+    tls_config: rustls::ServerConfig,
+    service: Arc<Service>,
+    header_timeout: Option<Duration>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let listener = TcpListener::bind(addr).await?;
+    let mut http = Http::new();
+    if let Some(timeout) = header_timeout {
+        http.http1_header_read_timeout(timeout);
+    }
+    let acceptor = TlsAcceptor::from(Arc::new(tls_config));
+
+    loop {
+        let (socket, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // most likely out of file descriptors. Back off a
+                // little rather than spinning on the error.
+                tracing::error!("accepting connection failed: {e:?}");
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        // The handshake happens in its own task so that a slow client
+        // cannot hold up accepting other connections.
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+        let http = http.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(socket).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::warn!("TLS handshake failed: {e:?}");
+                    return;
+                }
+            };
+            let conn = ConnContext::new(remote_addr);
+            let svc = service_fn(move |req| {
+                let s = service.clone();
+                let conn = conn.clone();
+                async move { s.serve(req, conn).await }
+            });
+            if let Err(e) = http.serve_connection(stream, svc).await {
+                tracing::error!("connection error: {e:?}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{archive, write_file, TestDirs, LAYER};
+
+    const PARENT: &str = "89abcdef0123456789abcdef0123456789abcdef";
+
+    async fn get(service: &Service, uri: &str) -> Response<Body> {
+        let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let conn = ConnContext::new(([127, 0, 0, 1], 1234).into());
+
+        service.handle(req, conn).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn uploads_from_outside_the_upload_dir_are_refused() {
+        let dirs = TestDirs::new();
+        let service = Service::new(dirs.config(&[])).unwrap();
+        let outside = dirs.path("primary").join("outside.larch");
+        std::fs::write(&outside, b"layer").unwrap();
+        let climbing = dirs.path("upload").join("../primary/outside.larch");
+
+        for path in [
+            "../primary/outside.larch",
+            climbing.to_str().unwrap(),
+            outside.to_str().unwrap(),
+            "/etc/passwd",
+        ] {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(format!("/upload/{LAYER}"))
+                .header("upload-path", path)
+                .body(Body::empty())
+                .unwrap();
+            let conn = ConnContext::new(([127, 0, 0, 1], 1234).into());
+
+            let response = service.handle(req, conn).await.unwrap();
+
+            assert_eq!(response.status(), 400, "upload from {path}");
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["error"], "bad_upload_path", "upload from {path}");
+        }
+        assert!(outside.exists());
+    }
+
+    #[tokio::test]
+    async fn an_upload_path_that_is_no_archive_is_refused() {
+        let dirs = TestDirs::new();
+        let service = Service::new(dirs.config(&[])).unwrap();
+        let upload = dirs.path("upload").join("upload.larch");
+        std::fs::write(&upload, b"layer").unwrap();
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("/upload/{LAYER}"))
+            .header("upload-path", upload.to_str().unwrap())
+            .body(Body::empty())
+            .unwrap();
+        let conn = ConnContext::new(([127, 0, 0, 1], 1234).into());
+
+        let response = service.handle(req, conn).await.unwrap();
+
+        assert_eq!(response.status(), 422);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "invalid_archive");
+        assert!(!upload.exists());
+    }
+
+    #[tokio::test]
+    async fn a_layer_download_counts_once() {
+        let dirs = TestDirs::new();
+        let service = Service::new(dirs.config(&[])).unwrap();
+        // the child is cached, its parent only in primary
+        write_file(
+            &dirs
+                .path("local")
+                .join(&LAYER[..3])
+                .join(format!("{LAYER}.larch")),
+            &archive(&[
+                (LayerFileEnum::NodeDictionaryBlocks, b"nodes"),
+                (LayerFileEnum::Parent, PARENT.as_bytes()),
+            ]),
+        );
+        write_file(
+            &dirs
+                .path("primary")
+                .join(&PARENT[..3])
+                .join(format!("{PARENT}.larch")),
+            &archive(&[(LayerFileEnum::NodeDictionaryBlocks, b"nodes")]),
+        );
+
+        for layer in [LAYER, PARENT] {
+            let response = get(&service, &format!("/layer/{layer}")).await;
+            assert_eq!(response.status(), 200);
+            if layer == LAYER {
+                assert_eq!(
+                    response.headers()["Link"],
+                    format!("</layer/{PARENT}>; rel=preload").as_str()
+                );
+            }
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+        }
+
+        let response = get(&service, "/metrics").await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let metrics = String::from_utf8(body.to_vec()).unwrap();
+        assert!(metrics.contains("\nlayer_service_local_hits_total 1\n"));
+        assert!(metrics.contains("\nlayer_service_primary_fallbacks_total 1\n"));
+    }
+}
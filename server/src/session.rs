@@ -0,0 +1,311 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, SeekFrom},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures::Stream;
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::Mutex,
+};
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+/// State of a single resumable upload. The bytes received so far live
+/// in a file in the upload dir.
+pub struct UploadSession {
+    path: PathBuf,
+    offset: u64,
+    total: Option<u64>,
+    last_activity: Instant,
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    NotFound,
+    OffsetMismatch { offset: u64 },
+    LengthMismatch { expected: u64, received: u64 },
+    BeyondTotal { end: u64, total: u64 },
+    TooLarge { limit: u64 },
+    TooManyUploads,
+    Incomplete { offset: u64, total: Option<u64> },
+    Io(io::Error),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "upload session not found"),
+            Self::OffsetMismatch { offset } => {
+                write!(f, "chunk does not start at current offset {offset}")
+            }
+            Self::LengthMismatch { expected, received } => {
+                write!(
+                    f,
+                    "expected {expected} bytes in chunk but received {received}"
+                )
+            }
+            Self::BeyondTotal { end, total } => {
+                write!(f, "chunk ends at {end}, past the total of {total} bytes")
+            }
+            Self::TooLarge { limit } => write!(f, "upload exceeds the limit of {limit} bytes"),
+            Self::TooManyUploads => write!(f, "too many concurrent uploads"),
+            Self::Incomplete { offset, total } => match total {
+                Some(total) => write!(f, "upload incomplete: received {offset} of {total} bytes"),
+                None => write!(f, "upload incomplete: total size was never announced"),
+            },
+            Self::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl From<io::Error> for SessionError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Resumable upload sessions. Sessions that see no activity for longer
+/// than the ttl are dropped along with their data.
+pub struct UploadSessions {
+    upload_path: PathBuf,
+    ttl: Duration,
+    max_size: Option<u64>,
+    sessions: Mutex<HashMap<Uuid, Arc<Mutex<UploadSession>>>>,
+}
+
+impl UploadSessions {
+    pub fn new(upload_path: PathBuf, ttl: Duration, max_size: Option<u64>) -> Self {
+        Self {
+            upload_path,
+            ttl,
+            max_size,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn start(&self) -> io::Result<Uuid> {
+        self.prune().await;
+
+        let id = Uuid::new_v4();
+        let mut path = self.upload_path.clone();
+        path.push(format!(".session-{id}"));
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+
+        let session = UploadSession {
+            path,
+            offset: 0,
+            total: None,
+            last_activity: Instant::now(),
+        };
+        self.sessions
+            .lock()
+            .await
+            .insert(id, Arc::new(Mutex::new(session)));
+
+        Ok(id)
+    }
+
+    async fn get(&self, id: Uuid) -> Result<Arc<Mutex<UploadSession>>, SessionError> {
+        self.prune().await;
+        self.sessions
+            .lock()
+            .await
+            .get(&id)
+            .cloned()
+            .ok_or(SessionError::NotFound)
+    }
+
+    /// The number of bytes received so far.
+    pub async fn offset(&self, id: Uuid) -> Result<u64, SessionError> {
+        let session = self.get(id).await?;
+        let session = session.lock().await;
+
+        Ok(session.offset)
+    }
+
+    /// Append a chunk covering the bytes `start..end` of the upload.
+    /// A chunk has to start exactly where the previous one ended, and
+    /// neither it nor the announced total may exceed the maximum size.
+    /// If the chunk isn't received in full, or turns out to be longer
+    /// than announced, it is discarded entirely so that the client can
+    /// simply retry it.
+    pub async fn append(
+        &self,
+        id: Uuid,
+        start: u64,
+        end: u64,
+        total: Option<u64>,
+        mut stream: impl Stream<Item = Result<Bytes, hyper::Error>> + Unpin,
+    ) -> Result<u64, SessionError> {
+        let session = self.get(id).await?;
+        let mut session = session.lock().await;
+        if session.offset != start {
+            return Err(SessionError::OffsetMismatch {
+                offset: session.offset,
+            });
+        }
+        // a chunk that doesn't announce the total is still bound by the
+        // one announced earlier
+        if let Some(total) = total.or(session.total) {
+            if end > total {
+                return Err(SessionError::BeyondTotal { end, total });
+            }
+        }
+        if let Some(limit) = self.max_size {
+            if end > limit || total.is_some_and(|total| total > limit) {
+                return Err(SessionError::TooLarge { limit });
+            }
+        }
+
+        let mut file = OpenOptions::new().write(true).open(&session.path).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut received = 0;
+        let mut result = Ok(());
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(mut bytes) => {
+                    received += bytes.len() as u64;
+                    // stop right away rather than write whatever the
+                    // client keeps sending
+                    if received > end - start {
+                        result = Err(SessionError::LengthMismatch {
+                            expected: end - start,
+                            received,
+                        });
+                        break;
+                    }
+                    if let Err(e) = file.write_all_buf(&mut bytes).await {
+                        result = Err(SessionError::Io(e));
+                        break;
+                    }
+                }
+                Err(e) => {
+                    result = Err(SessionError::Io(io::Error::other(e)));
+                    break;
+                }
+            }
+        }
+        if result.is_ok() && received < end - start {
+            result = Err(SessionError::LengthMismatch {
+                expected: end - start,
+                received,
+            });
+        }
+        if result.is_ok() {
+            result = file.flush().await.map_err(SessionError::Io);
+        }
+        session.last_activity = Instant::now();
+        if let Err(e) = result {
+            // throw away the partial chunk
+            file.set_len(session.offset).await?;
+            return Err(e);
+        }
+
+        session.offset = end;
+        if total.is_some() {
+            session.total = total;
+        }
+
+        Ok(session.offset)
+    }
+
+    /// End a session whose upload is complete, returning the path of
+    /// the uploaded file. The caller is responsible for the file.
+    pub async fn finish(&self, id: Uuid) -> Result<PathBuf, SessionError> {
+        let session = self.get(id).await?;
+        let session = session.lock().await;
+        if session.total != Some(session.offset) {
+            return Err(SessionError::Incomplete {
+                offset: session.offset,
+                total: session.total,
+            });
+        }
+        self.sessions.lock().await.remove(&id);
+
+        Ok(session.path.clone())
+    }
+
+    /// Drop sessions that have been idle for longer than the ttl.
+    async fn prune(&self) {
+        let mut expired = Vec::new();
+        {
+            let mut sessions = self.sessions.lock().await;
+            sessions.retain(|_, session| match session.try_lock() {
+                Ok(session) if session.last_activity.elapsed() > self.ttl => {
+                    expired.push(session.path.clone());
+                    false
+                }
+                // sessions that are busy are obviously not abandoned
+                _ => true,
+            });
+        }
+
+        for path in expired {
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                tracing::error!("could not remove expired upload session: {e:?}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TestDirs;
+
+    fn body(chunks: &[&'static [u8]]) -> hyper::Body {
+        let chunks: Vec<io::Result<Bytes>> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::from_static(chunk)))
+            .collect();
+        hyper::Body::wrap_stream(futures::stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn chunks_are_held_to_their_range_and_the_limit() {
+        let dirs = TestDirs::new();
+        let sessions = UploadSessions::new(dirs.path("upload"), Duration::from_secs(60), Some(16));
+        let id = sessions.start().await.unwrap();
+        let path = dirs.path("upload").join(format!(".session-{id}"));
+
+        let result = sessions
+            .append(id, 0, 1, None, body(&[b"0", b"123456789"]))
+            .await;
+        assert!(matches!(
+            result,
+            Err(SessionError::LengthMismatch {
+                expected: 1,
+                received: 10
+            })
+        ));
+        assert_eq!(sessions.offset(id).await.unwrap(), 0);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        let result = sessions.append(id, 0, 4, Some(32), body(&[b"0123"])).await;
+        assert!(matches!(result, Err(SessionError::TooLarge { limit: 16 })));
+
+        sessions
+            .append(id, 0, 4, Some(8), body(&[b"0123"]))
+            .await
+            .unwrap();
+        let result = sessions.append(id, 4, 12, None, body(&[b"45678901"])).await;
+        assert!(matches!(
+            result,
+            Err(SessionError::BeyondTotal { end: 12, total: 8 })
+        ));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4);
+    }
+}
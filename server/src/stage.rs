@@ -0,0 +1,218 @@
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    path::PathBuf,
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::Stream;
+use num_traits::FromPrimitive;
+use terminus_store::storage::{
+    archive::ArchiveFilePresenceHeader,
+    consts::{
+        LayerFileEnum, BASE_LAYER_REQUIRED_FILES, CHILD_LAYER_REQUIRED_FILES, FILENAME_ENUM_MAP,
+        SHARED_REQUIRED_FILES,
+    },
+};
+use terminus_store::structure::LateLogArrayBufBuilder;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+};
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+use super::name::{layer_name_to_string, LayerName};
+
+#[derive(Debug)]
+pub enum StageError {
+    NotFound,
+    TooLarge { limit: u64 },
+    MissingFiles(Vec<LayerFileEnum>),
+    Io(io::Error),
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "nothing staged for layer"),
+            Self::TooLarge { limit } => write!(f, "staged file exceeds the limit of {limit} bytes"),
+            Self::MissingFiles(files) => {
+                write!(f, "{} required files are missing", files.len())
+            }
+            Self::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for StageError {}
+
+impl From<io::Error> for StageError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Component files of layers that are uploaded one by one, waiting to
+/// be assembled into an archive. Every layer gets a directory in which
+/// each file is stored under the number of its `LayerFileEnum`.
+pub struct StagingArea {
+    path: PathBuf,
+}
+
+impl StagingArea {
+    pub fn new(upload_path: PathBuf) -> Self {
+        Self {
+            path: upload_path.join("stage"),
+        }
+    }
+
+    fn layer_path(&self, layer: LayerName) -> PathBuf {
+        self.path.join(layer_name_to_string(layer))
+    }
+
+    /// Store a component file of a layer, replacing any earlier version
+    /// of it. The file only becomes visible once it was received in
+    /// full.
+    pub async fn write_file(
+        &self,
+        layer: LayerName,
+        file: LayerFileEnum,
+        mut stream: impl Stream<Item = Result<Bytes, hyper::Error>> + Unpin,
+        limit: Option<u64>,
+    ) -> Result<(), StageError> {
+        let dir = self.layer_path(layer);
+        tokio::fs::create_dir_all(&dir).await?;
+        let path = dir.join((file as usize).to_string());
+        let partial = dir.join(format!(".{}-{}", file as usize, Uuid::new_v4()));
+
+        let mut out = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&partial)
+            .await?;
+        let mut total = 0;
+        let mut result = Ok(());
+        while let Some(chunk) = stream.next().await {
+            let mut bytes = match chunk {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    result = Err(StageError::Io(io::Error::other(e)));
+                    break;
+                }
+            };
+            total += bytes.len() as u64;
+            if let Some(limit) = limit.filter(|limit| total > *limit) {
+                result = Err(StageError::TooLarge { limit });
+                break;
+            }
+            if let Err(e) = out.write_all_buf(&mut bytes).await {
+                result = Err(StageError::Io(e));
+                break;
+            }
+        }
+        if result.is_ok() {
+            result = out.sync_all().await.map_err(StageError::Io);
+        }
+        if result.is_ok() {
+            result = tokio::fs::rename(&partial, &path)
+                .await
+                .map_err(StageError::Io);
+        }
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&partial).await;
+        }
+
+        result
+    }
+
+    /// The staged files of a layer, in archive order.
+    async fn staged_files(
+        &self,
+        layer: LayerName,
+    ) -> Result<Vec<(LayerFileEnum, PathBuf)>, StageError> {
+        let mut entries = match tokio::fs::read_dir(self.layer_path(layer)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(StageError::NotFound),
+            Err(e) => return Err(e.into()),
+        };
+        let mut files = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            // partial files start with a dot and never parse
+            let file = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+                .and_then(LayerFileEnum::from_usize);
+            if let Some(file) = file {
+                files.push((file, entry.path()));
+            }
+        }
+        files.sort_by_key(|(file, _)| *file as usize);
+
+        Ok(files)
+    }
+
+    /// Assemble the staged files of a layer into an archive, returning
+    /// the path of the archive. The archive lives in the staging
+    /// directory of the layer until `discard` is called, so the caller
+    /// should move it elsewhere first.
+    pub async fn assemble(&self, layer: LayerName) -> Result<PathBuf, StageError> {
+        let files = self.staged_files(layer).await?;
+        let missing = missing_files(files.iter().map(|(file, _)| *file));
+        if !missing.is_empty() {
+            return Err(StageError::MissingFiles(missing));
+        }
+
+        let mut offsets = LateLogArrayBufBuilder::new(BytesMut::new());
+        let mut tally = 0;
+        for (_, path) in &files {
+            tally += tokio::fs::metadata(path).await?.len();
+            offsets.push(tally);
+        }
+        let presence = ArchiveFilePresenceHeader::from_present(files.iter().map(|(file, _)| *file));
+        let mut header = BytesMut::new();
+        header.put_u64(presence.inner());
+        header.extend(offsets.finalize_header_first());
+
+        let path = self
+            .layer_path(layer)
+            .join(format!(".archive-{}.larch", Uuid::new_v4()));
+        let mut out = File::create(&path).await?;
+        out.write_all(&header).await?;
+        for (_, file) in &files {
+            let mut file = File::open(file).await?;
+            tokio::io::copy(&mut file, &mut out).await?;
+        }
+        out.sync_all().await?;
+
+        Ok(path)
+    }
+
+    /// Throw away everything staged for a layer.
+    pub async fn discard(&self, layer: LayerName) -> io::Result<()> {
+        match tokio::fs::remove_dir_all(self.layer_path(layer)).await {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// The files a layer can't do without that are not among `present`.
+/// Layers with a parent file are child layers, anything else is a base
+/// layer.
+pub fn missing_files(present: impl Iterator<Item = LayerFileEnum> + Clone) -> Vec<LayerFileEnum> {
+    let is_child = present.clone().any(|file| file == LayerFileEnum::Parent);
+    let required: &[&str] = if is_child {
+        &CHILD_LAYER_REQUIRED_FILES
+    } else {
+        &BASE_LAYER_REQUIRED_FILES
+    };
+
+    SHARED_REQUIRED_FILES
+        .iter()
+        .chain(required)
+        .map(|name| FILENAME_ENUM_MAP[name])
+        .filter(|file| !present.clone().any(|present| present == *file))
+        .collect()
+}
@@ -0,0 +1,66 @@
+use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState};
+use opentelemetry_otlp::WithExportConfig;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use uuid::Uuid;
+
+use super::config::Config;
+
+/// Set up logging to stderr and, if an OTLP endpoint is configured,
+/// exporting of spans as OpenTelemetry traces.
+pub fn init(config: &Config) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr));
+
+    match &config.otlp_endpoint {
+        Some(endpoint) => {
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .install_batch(opentelemetry::runtime::Tokio)?;
+            registry
+                .with(tracing_opentelemetry::layer().with_tracer(tracer))
+                .init();
+        }
+        None => registry.init(),
+    }
+
+    Ok(())
+}
+
+/// Flush any spans that haven't been exported yet.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Use a client provided request id as the trace id of a request
+/// span. Only ids that are 128 bit hex strings (dashes are ignored,
+/// so uuids work) can be used this way. Anything else is left alone.
+pub fn set_request_id(span: &tracing::Span, request_id: &str) {
+    let hex: String = request_id.chars().filter(|c| *c != '-').collect();
+    if hex.len() != 32 {
+        return;
+    }
+    let trace_id = match TraceId::from_hex(&hex) {
+        Ok(trace_id) if trace_id != TraceId::INVALID => trace_id,
+        _ => return,
+    };
+
+    // The client didn't send us a span, so make one up for the
+    // remote parent.
+    let span_id = SpanId::from_bytes((Uuid::new_v4().as_u64_pair().0 | 1).to_be_bytes());
+    let span_context = SpanContext::new(
+        trace_id,
+        span_id,
+        TraceFlags::SAMPLED,
+        true,
+        TraceState::default(),
+    );
+    span.set_parent(opentelemetry::Context::new().with_remote_span_context(span_context));
+}
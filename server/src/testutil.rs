@@ -0,0 +1,92 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use bytes::{BufMut, BytesMut};
+use clap::Parser;
+use terminus_store::{
+    storage::{archive::ArchiveFilePresenceHeader, consts::LayerFileEnum},
+    structure::LateLogArrayBufBuilder,
+};
+
+use crate::{config::Config, manager::LayerManager};
+
+pub const LAYER: &str = "0123456789abcdef0123456789abcdef01234567";
+
+static NEXT_ROOT: AtomicUsize = AtomicUsize::new(0);
+
+/// Fresh primary, local, upload and scratch directories, removed
+/// again when dropped.
+pub struct TestDirs {
+    root: PathBuf,
+}
+
+impl TestDirs {
+    pub fn new() -> Self {
+        let root = std::env::temp_dir().join(format!(
+            "layer-service-{}-{}",
+            std::process::id(),
+            NEXT_ROOT.fetch_add(1, Ordering::Relaxed)
+        ));
+        for dir in ["primary", "local", "upload", "scratch"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+
+        TestDirs { root }
+    }
+
+    pub fn path(&self, dir: &str) -> PathBuf {
+        self.root.join(dir)
+    }
+
+    /// A config using these directories, set up further by `args`.
+    pub fn config(&self, args: &[&str]) -> Config {
+        let mut argv = vec!["layer-service".to_string()];
+        for dir in ["primary", "local", "upload", "scratch"] {
+            argv.push(format!("--{dir}"));
+            argv.push(self.path(dir).to_string_lossy().into_owned());
+        }
+        argv.extend(args.iter().map(|arg| arg.to_string()));
+
+        Config::parse_from(argv)
+    }
+
+    pub fn manager(&self, args: &[&str]) -> Arc<LayerManager> {
+        Arc::new(LayerManager::new(&self.config(args)))
+    }
+}
+
+impl Drop for TestDirs {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+pub fn write_file(path: &Path, contents: &[u8]) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, contents).unwrap();
+}
+
+/// A layer archive of the given files, which must be in archive
+/// order.
+pub fn archive(files: &[(LayerFileEnum, &[u8])]) -> Vec<u8> {
+    let mut offsets = LateLogArrayBufBuilder::new(BytesMut::new());
+    let mut tally = 0;
+    for (_, contents) in files {
+        tally += contents.len() as u64;
+        offsets.push(tally);
+    }
+    let presence = ArchiveFilePresenceHeader::from_present(files.iter().map(|(file, _)| *file));
+    let mut archive = BytesMut::new();
+    archive.put_u64(presence.inner());
+    archive.extend(offsets.finalize_header_first());
+    for (_, contents) in files {
+        archive.extend_from_slice(contents);
+    }
+
+    archive.to_vec()
+}
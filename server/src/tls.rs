@@ -0,0 +1,85 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufReader},
+};
+
+use rustls::{
+    cipher_suite::*,
+    version::{TLS12, TLS13},
+    Certificate, PrivateKey, ServerConfig, SupportedCipherSuite, SupportedProtocolVersion,
+};
+
+use super::config::{Config, TlsCipherProfile, TlsVersion};
+
+static MODERN_CIPHER_SUITES: &[SupportedCipherSuite] = &[
+    TLS13_AES_256_GCM_SHA384,
+    TLS13_AES_128_GCM_SHA256,
+    TLS13_CHACHA20_POLY1305_SHA256,
+    TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+];
+
+/// Build the rustls server configuration, or None if TLS is not
+/// configured. Incomplete or insecure settings are rejected here so
+/// that they fail at startup rather than at the first handshake.
+pub fn server_config(
+    config: &Config,
+) -> Result<Option<ServerConfig>, Box<dyn Error + Send + Sync>> {
+    let (cert_path, key_path) = match (&config.tls_cert, &config.tls_key) {
+        (None, None) => return Ok(None),
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        _ => return Err("--tls-cert and --tls-key must be given together".into()),
+    };
+
+    let versions: &[&'static SupportedProtocolVersion] = match config.tls_min_version {
+        TlsVersion::V1_2 => &[&TLS13, &TLS12],
+        TlsVersion::V1_3 => &[&TLS13],
+    };
+    let cipher_suites = match config.tls_cipher_profile {
+        TlsCipherProfile::Modern => MODERN_CIPHER_SUITES,
+        TlsCipherProfile::Compatible => rustls::DEFAULT_CIPHER_SUITES,
+    };
+
+    let mut server_config = ServerConfig::builder()
+        .with_cipher_suites(cipher_suites)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)?
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert_path)?, load_key(key_path)?)?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(Some(server_config))
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificates found in {path}"),
+        ));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("no private key found in {path}"),
+    ))
+}
@@ -0,0 +1,143 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use super::name::{layer_name_to_string, LayerName};
+
+/// Upper bounds in milliseconds of the warm-up latency histogram
+/// buckets. Anything slower lands in a final overflow bucket.
+pub const WARMUP_BUCKETS_MS: [u64; 8] = [10, 50, 100, 500, 1_000, 5_000, 10_000, 60_000];
+
+/// How many completed warm-ups are kept for /recent-cache.
+const RECENT_WARMUPS: usize = 100;
+
+/// A layer that was requested from primary and has not been served
+/// from the local cache since.
+struct Pending {
+    requested_at: Instant,
+    cached_at: Option<Instant>,
+}
+
+/// A layer that went from being requested cold to being served warm.
+#[derive(Clone)]
+pub struct Warmup {
+    pub layer: LayerName,
+    /// Time from the cold request until the copy finished
+    pub copy: Option<Duration>,
+    /// Time from the cold request until the first local hit
+    pub latency: Duration,
+    pub served_at: SystemTime,
+}
+
+#[derive(Default)]
+struct Inner {
+    pending: HashMap<LayerName, Pending>,
+    recent: VecDeque<Warmup>,
+    buckets: [u64; WARMUP_BUCKETS_MS.len() + 1],
+    sum: Duration,
+}
+
+/// Correlates the request that triggered caching a layer with the first
+/// request served from the copy, to measure how long layers take to
+/// warm up.
+#[derive(Default)]
+pub struct WarmupTracker {
+    inner: Mutex<Inner>,
+}
+
+pub struct WarmupHistogram {
+    /// Count per bucket of `WARMUP_BUCKETS_MS`, plus the overflow bucket
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl WarmupTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A layer was served from primary and is going to be cached. Only
+    /// the first such request counts.
+    pub fn requested(&self, layer: LayerName) {
+        self.inner
+            .lock()
+            .unwrap()
+            .pending
+            .entry(layer)
+            .or_insert_with(|| Pending {
+                requested_at: Instant::now(),
+                cached_at: None,
+            });
+    }
+
+    /// The copy of a requested layer landed in the local cache.
+    pub fn cached(&self, layer: LayerName) {
+        if let Some(pending) = self.inner.lock().unwrap().pending.get_mut(&layer) {
+            pending.cached_at.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// The layer won't end up in the local cache this time around.
+    pub fn abandoned(&self, layer: LayerName) {
+        self.inner.lock().unwrap().pending.remove(&layer);
+    }
+
+    /// A layer was served from the local cache. If this is the first
+    /// time since it was requested cold, record how long that took.
+    pub fn served_locally(&self, layer: LayerName) {
+        let mut inner = self.inner.lock().unwrap();
+        let pending = match inner.pending.remove(&layer) {
+            Some(pending) => pending,
+            None => return,
+        };
+        let warmup = Warmup {
+            layer,
+            copy: pending
+                .cached_at
+                .map(|cached_at| cached_at - pending.requested_at),
+            latency: pending.requested_at.elapsed(),
+            served_at: SystemTime::now(),
+        };
+        tracing::info!(
+            layer = %layer_name_to_string(layer),
+            warmup_ms = warmup.latency.as_millis() as u64,
+            "layer first served from cache"
+        );
+
+        let millis = warmup.latency.as_millis() as u64;
+        let bucket = WARMUP_BUCKETS_MS
+            .iter()
+            .position(|bound| millis <= *bound)
+            .unwrap_or(WARMUP_BUCKETS_MS.len());
+        inner.buckets[bucket] += 1;
+        inner.sum += warmup.latency;
+        if inner.recent.len() == RECENT_WARMUPS {
+            inner.recent.pop_front();
+        }
+        inner.recent.push_back(warmup);
+    }
+
+    /// The most recent warm-ups, newest first.
+    pub fn recent(&self) -> Vec<Warmup> {
+        self.inner
+            .lock()
+            .unwrap()
+            .recent
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    pub fn histogram(&self) -> WarmupHistogram {
+        let inner = self.inner.lock().unwrap();
+        WarmupHistogram {
+            buckets: inner.buckets.to_vec(),
+            count: inner.buckets.iter().sum(),
+            sum: inner.sum,
+        }
+    }
+}